@@ -1,84 +1,37 @@
 use bitvec::prelude::*;
-use core::{marker::PhantomData, mem};
+use core::mem;
 use std::io;
 
-/// Phantom type representing a buffer to read from.
+/// `ByteBuffer` is a buffer consisting of one in-progress byte that is being
+/// written. `idx` refers to the next bit to write into.
 #[derive(Debug)]
-struct ReadBuffer;
-
-/// Phantom type representing a buffer to write to.
-#[derive(Debug)]
-struct WriteBuffer;
-
-/// `ByteBuffer` is a buffer consisting of one in-progress byte that is either
-/// being read or written.
-///
-/// The behavior is slightly different depending on whether a read or write is
-/// happening:
-/// - If reading, `idx` will refer to the next bit to read from.
-/// - If writing, `idx` will refer to the next bit to write into.
-///
-/// As a result, during reading, `byte` will always contain a full byte of data,
-/// while during writing, `byte` will start out incomplete and be filled over
-/// time.
-#[derive(Debug)]
-struct ByteBuffer<T> {
-    _kind: PhantomData<T>,
+struct ByteBuffer {
     byte: BitArray<[u8; 1]>,
     idx: usize,
 }
 
-impl<T> ByteBuffer<T> {
+impl ByteBuffer {
     const BITS: usize = 8;
 
     fn bits_left(&self) -> usize {
         Self::BITS - self.idx
     }
 
-    /// When reading, returns `true` if the byte has been fully read.
-    /// When writing, returns `true` if the byte-to-write is filled.
+    /// Returns `true` if the byte-to-write is filled.
     fn needs_flush(&self) -> bool {
         self.idx == Self::BITS
     }
 }
 
-impl ByteBuffer<ReadBuffer> {
-    fn new_read() -> Self {
-        Self {
-            _kind: PhantomData,
-            byte: BitArray::ZERO,
-            idx: Self::BITS,
-        }
-    }
-
-    fn read<T>(&mut self, slice: &mut BitSlice<T>) -> usize
-    where
-        T: BitStore,
-    {
-        let bit_count = self.bits_left().min(slice.len());
-        let buffer_bits = &self.byte[self.idx..self.idx + bit_count];
-
-        let bits_to_set = &mut slice[..bit_count];
-        bits_to_set.clone_from_bitslice(buffer_bits);
-
-        self.idx += bit_count;
-        bit_count
-    }
-}
-
-impl From<[u8; 1]> for ByteBuffer<ReadBuffer> {
-    fn from(buf: [u8; 1]) -> Self {
-        Self {
-            _kind: PhantomData,
-            byte: buf.into(),
-            idx: 0,
-        }
-    }
-}
+/// The most bits `BitReader::peek_bits` can return at once.
+pub const MAX_PEEK_BITS: u32 = 32;
 
 #[derive(Debug)]
 pub struct BitReader<'a, R> {
-    buffer: ByteBuffer<ReadBuffer>,
+    /// Bits that have been read from `inner` but not consumed yet, starting
+    /// from the least-significant bit.
+    bits: u64,
+    bit_count: u32,
     inner: &'a mut R,
 }
 
@@ -86,39 +39,87 @@ impl<'a, R> BitReader<'a, R>
 where
     R: io::Read,
 {
+    /// Creates a reader over `inner`. Looking ahead with `peek_bits` reads up
+    /// to 4 bytes from `inner` that haven't been consumed, so to read on
+    /// after the bits that are needed, use `into_inner` instead of `inner`.
     pub fn new(inner: &'a mut R) -> Self {
         Self {
-            buffer: ByteBuffer::new_read(),
+            bits: 0,
+            bit_count: 0,
             inner,
         }
     }
 
-    /// Precondition: `self.buffer.needs_flush()`
+    /// Returns a reader that continues right after the last byte that has been
+    /// partially or fully consumed: the bytes that have been read ahead,
+    /// followed by the inner reader.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn into_inner(mut self) -> io::Chain<io::Cursor<Vec<u8>>, &'a mut R> {
+        self.skip_to_byte_end();
+        let byte_count = (self.bit_count / 8) as usize;
+        let bytes = self.bits.to_le_bytes()[..byte_count].to_vec();
+        io::Read::chain(io::Cursor::new(bytes), self.inner)
+    }
+
     fn read_next_byte(&mut self) -> io::Result<()> {
         let mut buf = [0; 1];
         self.inner.read_exact(&mut buf)?;
-        self.buffer = buf.into();
+        self.bits |= u64::from(buf[0]) << self.bit_count;
+        self.bit_count += 8;
+        Ok(())
+    }
+
+    /// Returns the next `bit_count` bits without consuming them, with the
+    /// first bit in the least-significant bit. Past the end of the input, the
+    /// missing bits are zeros, and consuming them fails.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn peek_bits(&mut self, bit_count: u32) -> io::Result<u32> {
+        assert!(bit_count <= MAX_PEEK_BITS);
+        while self.bit_count < bit_count {
+            match self.read_next_byte() {
+                Ok(()) => {}
+                Err(e) if matches!(e.kind(), io::ErrorKind::UnexpectedEof) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok((self.bits & ((1 << bit_count) - 1)) as u32)
+    }
+
+    /// Consumes bits that have been peeked at.
+    pub fn consume(&mut self, bit_count: u32) -> io::Result<()> {
+        if bit_count > self.bit_count {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected end of compressed data",
+            ));
+        }
+
+        self.bits >>= bit_count;
+        self.bit_count -= bit_count;
         Ok(())
     }
 
-    pub fn read_exact<T>(&mut self, mut slice: &mut BitSlice<T>) -> io::Result<()>
+    pub fn read_bits(&mut self, bit_count: u32) -> io::Result<u32> {
+        let bits = self.peek_bits(bit_count)?;
+        self.consume(bit_count)?;
+        Ok(bits)
+    }
+
+    pub fn read_exact<T>(&mut self, slice: &mut BitSlice<T>) -> io::Result<()>
     where
         T: BitStore,
     {
-        while !slice.is_empty() {
-            if self.buffer.needs_flush() {
-                self.read_next_byte()?;
-            }
-
-            let bit_read_count = self.buffer.read(slice);
-            slice = &mut slice[bit_read_count..];
+        for idx in 0..slice.len() {
+            let bit = self.read_bool()?;
+            slice.set(idx, bit);
         }
 
         Ok(())
     }
 
     pub fn is_eof(&mut self) -> io::Result<bool> {
-        if !self.buffer.needs_flush() {
+        if self.bit_count > 0 {
             return Ok(false);
         }
 
@@ -130,18 +131,15 @@ where
     }
 
     pub fn read_bool(&mut self) -> io::Result<bool> {
-        let arr = bits![mut u8, Lsb0; 0; 1];
-        self.read_exact(arr)?;
-        Ok(arr[0])
+        Ok(self.read_bits(1)? == 1)
     }
 
     pub fn read_u8_from_bits(&mut self, bit_count: usize) -> io::Result<u8> {
         assert!(bit_count <= 8);
-        let mut bv = <BitVec<u8>>::with_capacity(bit_count);
-        bv.resize(bit_count, false);
-
-        self.read_exact(bv.as_mut_bitslice())?;
-        Ok(bv.load_le::<u8>())
+        Ok(self
+            .read_bits(bit_count.try_into().unwrap())?
+            .try_into()
+            .unwrap())
     }
 
     pub fn read_u8(&mut self) -> io::Result<u8> {
@@ -150,11 +148,10 @@ where
 
     pub fn read_u16_from_bits(&mut self, bit_count: usize) -> io::Result<u16> {
         assert!(bit_count <= 16);
-        let mut bv = <BitVec<u16>>::with_capacity(bit_count);
-        bv.resize(bit_count, false);
-
-        self.read_exact(bv.as_mut_bitslice())?;
-        Ok(bv.load_le::<u16>())
+        Ok(self
+            .read_bits(bit_count.try_into().unwrap())?
+            .try_into()
+            .unwrap())
     }
 
     pub fn read_u16(&mut self) -> io::Result<u16> {
@@ -163,26 +160,26 @@ where
 
     pub fn read_u32_from_bits(&mut self, bit_count: usize) -> io::Result<u32> {
         assert!(bit_count <= 32);
-        let mut bv = <BitVec<u32>>::with_capacity(bit_count);
-        bv.resize(bit_count, false);
-
-        self.read_exact(bv.as_mut_bitslice())?;
-        Ok(bv.load_le::<u32>())
+        self.read_bits(bit_count.try_into().unwrap())
     }
 
     pub fn read_u32(&mut self) -> io::Result<u32> {
         self.read_u32_from_bits(32)
     }
 
+    /// Skips the rest of the current, partially read byte.
     pub fn skip_to_byte_end(&mut self) {
-        self.buffer.idx = <ByteBuffer<R>>::BITS;
+        // Whole bytes are read at a time, so only the current byte can be
+        // partially consumed
+        let partial_bit_count = self.bit_count % 8;
+        self.bits >>= partial_bit_count;
+        self.bit_count -= partial_bit_count;
     }
 }
 
-impl ByteBuffer<WriteBuffer> {
+impl ByteBuffer {
     fn new_write() -> Self {
         Self {
-            _kind: PhantomData,
             byte: BitArray::ZERO,
             idx: 0,
         }
@@ -203,30 +200,49 @@ impl ByteBuffer<WriteBuffer> {
     }
 }
 
-impl From<ByteBuffer<WriteBuffer>> for u8 {
-    fn from(ByteBuffer { byte, .. }: ByteBuffer<WriteBuffer>) -> Self {
+impl From<ByteBuffer> for u8 {
+    fn from(ByteBuffer { byte, .. }: ByteBuffer) -> Self {
         let [byte] = byte.into_inner();
         byte
     }
 }
 
 #[derive(Debug)]
-pub struct BitWriter<'a, W> {
-    buffer: ByteBuffer<WriteBuffer>,
-    inner: &'a mut W,
+pub struct BitWriter<W> {
+    buffer: ByteBuffer,
+    inner: W,
 }
 
-impl<'a, W> BitWriter<'a, W>
+impl<W> BitWriter<W>
 where
     W: io::Write,
 {
-    pub fn new(inner: &'a mut W) -> Self {
+    pub fn new(inner: W) -> Self {
         Self {
             buffer: ByteBuffer::new_write(),
             inner,
         }
     }
 
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns the inner writer. Any bits of a partially written byte are
+    /// discarded, so call `flush_even_if_partial` first to keep them.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// The number of bits written to the current, partially filled byte.
+    pub fn pending_bit_count(&self) -> usize {
+        self.buffer.idx
+    }
+
     /// Flushes the current byte. If the byte has not been fully written to, it
     /// will be padded with zeros.
     pub fn flush_even_if_partial(&mut self) -> io::Result<()> {
@@ -255,7 +271,36 @@ where
         Ok(())
     }
 
+    pub fn write_bool(&mut self, bit: bool) -> io::Result<()> {
+        let arr = bits![mut u8, Lsb0; 0; 1];
+        arr.set(0, bit);
+        self.write_all(arr)
+    }
+
     pub fn write_u8(&mut self, byte: u8) -> io::Result<()> {
         self.write_all(byte.view_bits::<Lsb0>())
     }
+
+    /// Writes whole bytes, writing straight through to the inner writer when
+    /// the current byte is empty.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.buffer.idx == 0 {
+            return self.inner.write_all(bytes);
+        }
+
+        for &byte in bytes {
+            self.write_u8(byte)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_u16_from_bits(&mut self, value: u16, bit_count: usize) -> io::Result<()> {
+        assert!(bit_count <= 16);
+        self.write_all(&value.view_bits::<Lsb0>()[..bit_count])
+    }
+
+    pub fn write_u16(&mut self, value: u16) -> io::Result<()> {
+        self.write_u16_from_bits(value, 16)
+    }
 }
@@ -1,15 +1,49 @@
 use crate::{
-    bit_io::BitReader,
-    huffman::{DistanceEncoding, HuffmanTree},
-    lzss::{OutBuffer, Symbol},
+    bit_io::{BitReader, BitWriter},
+    huffman::{
+        self, CodeLengthSymbol, HuffmanCode, HuffmanTable, MAX_CODE_LENGTH,
+        MAX_CODE_LENGTH_CODE_LENGTH,
+    },
+    lzss::{
+        self, BinaryTree, HashChain, MatchFinder, OutBuffer, Symbol, SymbolCosts,
+        MAX_DISTANCE_BYTES,
+    },
+    verify::{VerifiedOut, Verifier},
 };
 use bitvec::prelude::*;
-use std::io;
+use std::{
+    borrow::Cow,
+    io::{self, Read},
+    iter,
+    ops::Range,
+};
+
+/// The number of literal/length codes that can appear in compressed data.
+const LENGTH_CODE_COUNT: usize = 286;
+
+/// The number of distance codes that can appear in compressed data.
+const DISTANCE_CODE_COUNT: usize = 30;
+
+/// The most input bytes the encoder puts in one block, which is the most a
+/// stored block can hold.
+const MAX_BYTES_PER_BLOCK: usize = u16::MAX as usize;
+
+/// `EncoderOptions::rsyncable` flushes at about one in `2^RSYNC_HASH_BITS`
+/// positions.
+const RSYNC_HASH_BITS: u32 = 12;
+
+/// The fewest bytes between two rsync flushes, so that repetitive input isn't
+/// flushed too often.
+const RSYNC_MIN_LEN: usize = 4096;
 
+/// How a block is encoded.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum DeflateEncoding {
+pub enum DeflateEncoding {
+    /// A stored block, holding the bytes as they are
     NoCompression,
+    /// Huffman coded with the codes defined by the format
     FixedHuffman,
+    /// Huffman coded with codes given in the block header
     DynamicHuffman,
 }
 
@@ -44,60 +78,36 @@ impl From<DeflateEncoding> for BitVec<u8> {
             DeflateEncoding::FixedHuffman => 0b01,
             DeflateEncoding::DynamicHuffman => 0b10,
         };
-        Self::from_element(bits)
+        let mut bit_vec = Self::from_element(bits);
+        bit_vec.truncate(2);
+        bit_vec
     }
 }
 
 fn parse_symbol<R>(
-    length_huffman_tree: &HuffmanTree,
-    distance_encoding: &DistanceEncoding,
+    literal_table: &HuffmanTable,
+    distance_table: &HuffmanTable,
     in_: &mut BitReader<R>,
 ) -> io::Result<Symbol>
 where
     R: io::Read,
 {
-    let length_code = length_huffman_tree.decode(in_)?;
+    let (length_code, length_minus_three) = literal_table.decode(in_)?;
 
     match length_code {
         0..=255 => Ok(Symbol::Literal(length_code.try_into().unwrap())),
         256 => Ok(Symbol::EndOfBlock),
         257..=285 => {
-            let length_code_minus_257: u8 = (length_code - 257).try_into().unwrap();
-            let length_minus_three = match length_code_minus_257 {
-                0..=7 => length_code_minus_257,
-                8..=27 => {
-                    let extra_bit_count = length_code_minus_257 / 4 - 1;
-                    let extra_bits = in_.read_u8_from_bits(extra_bit_count.into())?;
-
-                    (1 << (length_code_minus_257 / 4 + 1))
-                        + (1 << (length_code_minus_257 / 4 - 1)) * (length_code_minus_257 % 4)
-                        + extra_bits
-                }
-                28 => 255,
-                29.. => unreachable!(),
-            };
-
-            let distance_code: u8 = distance_encoding.decode(in_)?.try_into().unwrap();
-            let distance_minus_one = match distance_code {
-                0..=3 => distance_code.into(),
-                4..=29 => {
-                    let extra_bit_count = distance_code / 2 - 1;
-                    let extra_bits = in_.read_u16_from_bits(extra_bit_count.into())?;
-
-                    (1 << (distance_code / 2))
-                        + (1 << (distance_code / 2 - 1)) * u16::from(distance_code % 2)
-                        + extra_bits
-                }
-                30.. => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("distance code must be <= 29, got {distance_code}"),
-                    ))
-                }
-            };
+            let (distance_code, distance_minus_one) = distance_table.decode(in_)?;
+            if distance_code >= 30 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("distance code must be <= 29, got {distance_code}"),
+                ));
+            }
 
             Ok(Symbol::BackReference {
-                length_minus_three,
+                length_minus_three: length_minus_three.try_into().unwrap(),
                 distance_minus_one,
             })
         }
@@ -108,20 +118,36 @@ where
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 enum DecodeStage {
+    #[default]
     NewBlock,
-    ParsedMode {
+    StoredBlock {
         is_final: bool,
-        encoding: DeflateEncoding,
+        remaining_len: u16,
+    },
+    HuffmanBlock {
+        is_final: bool,
+        /// Borrowed for fixed Huffman blocks, so that their tables are only
+        /// built once
+        literal_table: Cow<'static, HuffmanTable>,
+        distance_table: Cow<'static, HuffmanTable>,
     },
     Complete,
 }
 
-impl Default for DecodeStage {
-    fn default() -> Self {
-        Self::NewBlock
-    }
+/// A part of a Deflate stream, as returned by `DeflateDecoder::symbols`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodedItem {
+    /// The start of a block.
+    BlockStart {
+        is_final: bool,
+        encoding: DeflateEncoding,
+    },
+
+    /// A symbol in the current block. The bytes of a stored block are given
+    /// as literals, and every block ends with `Symbol::EndOfBlock`.
+    Symbol(Symbol),
 }
 
 #[derive(Debug, Default)]
@@ -136,12 +162,27 @@ impl DeflateDecoder {
         Self::default()
     }
 
-    fn advance_stage<R, W>(&mut self, in_: &mut BitReader<R>, out: &mut W) -> io::Result<()>
+    /// Creates a decoder for data that was encoded with a preset dictionary,
+    /// which back-references can reach into as if it came right before the
+    /// data.
+    pub fn with_dictionary(dictionary: &[u8]) -> io::Result<Self> {
+        check_dictionary_len(dictionary, MAX_DISTANCE_BYTES)?;
+
+        let mut decoder = Self::new();
+        for &byte in dictionary {
+            decoder.out_buffer.push(byte);
+        }
+
+        Ok(decoder)
+    }
+
+    /// Reads the next block header or symbol, or returns `None` after the
+    /// final block.
+    fn next_item<R>(&mut self, in_: &mut BitReader<R>) -> io::Result<Option<DecodedItem>>
     where
         R: io::Read,
-        W: io::Write,
     {
-        match self.stage {
+        match &self.stage {
             DecodeStage::NewBlock => {
                 let is_final = in_.read_bool()?;
 
@@ -149,12 +190,7 @@ impl DeflateDecoder {
                 in_.read_exact(encoding_bits)?;
                 let encoding = (&*encoding_bits).try_into()?;
 
-                self.stage = DecodeStage::ParsedMode { is_final, encoding };
-
-                Ok(())
-            }
-            DecodeStage::ParsedMode { is_final, encoding } => {
-                match encoding {
+                self.stage = match encoding {
                     DeflateEncoding::NoCompression => {
                         in_.skip_to_byte_end();
 
@@ -168,20 +204,16 @@ impl DeflateDecoder {
                             ));
                         }
 
-                        for _ in 0..len {
-                            out.write_all(&[in_.read_u8()?])?;
+                        DecodeStage::StoredBlock {
+                            is_final,
+                            remaining_len: len,
                         }
                     }
-                    DeflateEncoding::FixedHuffman => {
-                        let literal_huffman_tree = HuffmanTree::fixed_literal();
-
-                        self.decode_huffman_block(
-                            in_,
-                            out,
-                            &literal_huffman_tree,
-                            &DistanceEncoding::Fixed,
-                        )?;
-                    }
+                    DeflateEncoding::FixedHuffman => DecodeStage::HuffmanBlock {
+                        is_final,
+                        literal_table: Cow::Borrowed(HuffmanTable::fixed_literal_length()),
+                        distance_table: Cow::Borrowed(HuffmanTable::fixed_distance()),
+                    },
                     DeflateEncoding::DynamicHuffman => {
                         let literal_code_length_count = in_.read_u16_from_bits(5)? + 257;
                         let distance_code_length_count = in_.read_u8_from_bits(5)? + 1;
@@ -194,85 +226,126 @@ impl DeflateDecoder {
                             code_lengths_in_symbol_order.push(code_length);
                         }
 
-                        let code_lengths_huffman_tree =
-                            HuffmanTree::dynamic_code_lengths(&code_lengths_in_symbol_order);
+                        let code_length_table =
+                            HuffmanTable::dynamic_code_lengths(&code_lengths_in_symbol_order)?;
 
-                        let literal_huffman_tree = code_lengths_huffman_tree
-                            .decode_code_lengths(literal_code_length_count.into(), in_)?;
-
-                        let distance_huffman_tree = code_lengths_huffman_tree
-                            .decode_code_lengths(distance_code_length_count.into(), in_)?;
-
-                        self.decode_huffman_block(
+                        // Both sets of code lengths are one sequence, so a run
+                        // can cross from one into the other
+                        let mut literal_code_lengths = code_length_table.decode_code_lengths(
+                            usize::from(literal_code_length_count)
+                                + usize::from(distance_code_length_count),
                             in_,
-                            out,
-                            &literal_huffman_tree,
-                            &DistanceEncoding::Dynamic(distance_huffman_tree),
                         )?;
+                        let distance_code_lengths =
+                            literal_code_lengths.split_off(literal_code_length_count.into());
+
+                        DecodeStage::HuffmanBlock {
+                            is_final,
+                            literal_table: Cow::Owned(HuffmanTable::literal_length(
+                                &literal_code_lengths,
+                            )?),
+                            distance_table: Cow::Owned(HuffmanTable::distance(
+                                &distance_code_lengths,
+                            )?),
+                        }
                     }
+                };
+
+                Ok(Some(DecodedItem::BlockStart { is_final, encoding }))
+            }
+            &DecodeStage::StoredBlock {
+                is_final,
+                remaining_len,
+            } => {
+                if remaining_len == 0 {
+                    self.end_block(is_final, in_);
+                    return Ok(Some(DecodedItem::Symbol(Symbol::EndOfBlock)));
                 }
 
-                if is_final {
-                    in_.skip_to_byte_end();
-                    out.flush()?;
-                    self.stage = DecodeStage::Complete;
-                } else {
-                    self.stage = DecodeStage::NewBlock;
+                let byte = in_.read_u8()?;
+                self.stage = DecodeStage::StoredBlock {
+                    is_final,
+                    remaining_len: remaining_len - 1,
+                };
+
+                Ok(Some(DecodedItem::Symbol(Symbol::Literal(byte))))
+            }
+            DecodeStage::HuffmanBlock {
+                is_final,
+                literal_table,
+                distance_table,
+            } => {
+                let is_final = *is_final;
+                let symbol = parse_symbol(literal_table, distance_table, in_)?;
+                if symbol == Symbol::EndOfBlock {
+                    self.end_block(is_final, in_);
                 }
 
-                Ok(())
+                Ok(Some(DecodedItem::Symbol(symbol)))
             }
-            DecodeStage::Complete => Ok(()),
+            DecodeStage::Complete => Ok(None),
         }
     }
 
-    fn decode_huffman_block<R, W>(
-        &mut self,
-        in_: &mut BitReader<R>,
-        out: &mut W,
-        literal_huffman_tree: &HuffmanTree,
-        distance_encoding: &DistanceEncoding,
-    ) -> io::Result<()>
+    fn end_block<R>(&mut self, is_final: bool, in_: &mut BitReader<R>)
     where
         R: io::Read,
+    {
+        if is_final {
+            in_.skip_to_byte_end();
+            self.stage = DecodeStage::Complete;
+        } else {
+            self.stage = DecodeStage::NewBlock;
+        }
+    }
+
+    /// Writes the bytes `symbol` decodes to.
+    fn write_symbol<W>(&mut self, symbol: Symbol, out: &mut W) -> io::Result<()>
+    where
         W: io::Write,
     {
-        loop {
-            let length_symbol = parse_symbol(literal_huffman_tree, distance_encoding, in_)?;
+        match symbol {
+            Symbol::Literal(literal) => {
+                out.write_all(&[literal])?;
+                self.out_buffer.push(literal);
+            }
+            Symbol::EndOfBlock => {}
+            Symbol::BackReference {
+                length_minus_three,
+                distance_minus_one,
+            } => {
+                let length = u16::from(length_minus_three) + 3;
+                for _ in 0..length {
+                    let byte = self
+                        .out_buffer
+                        .get(distance_minus_one.into())
+                        .ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!(
+                                    "invalid backreference with distance {}",
+                                    distance_minus_one + 1,
+                                ),
+                            )
+                        })?;
 
-            match length_symbol {
-                Symbol::Literal(literal) => {
-                    out.write_all(&[literal])?;
-                    self.out_buffer.push(literal);
-                }
-                Symbol::EndOfBlock => {
-                    return Ok(());
-                }
-                Symbol::BackReference {
-                    length_minus_three,
-                    distance_minus_one,
-                } => {
-                    let length = u16::from(length_minus_three) + 3;
-                    for _ in 0..length {
-                        let byte =
-                            self.out_buffer
-                                .get(distance_minus_one.into())
-                                .ok_or_else(|| {
-                                    io::Error::new(
-                                        io::ErrorKind::InvalidData,
-                                        format!(
-                                            "invalid backreference with distance {}",
-                                            distance_minus_one + 1,
-                                        ),
-                                    )
-                                })?;
-
-                        out.write_all(&[byte])?;
-                        self.out_buffer.push(byte);
-                    }
+                    out.write_all(&[byte])?;
+                    self.out_buffer.push(byte);
                 }
             }
         }
+
+        Ok(())
+    }
+
+    /// Returns an iterator over the block headers and symbols in `in_`,
+    /// without decoding the symbols to bytes. Back-references are not
+    /// checked against the data they refer to.
+    pub fn symbols<'a, 'r, R>(&'a mut self, in_: &'a mut BitReader<'r, R>) -> Symbols<'a, 'r, R>
+    where
+        R: io::Read,
+    {
+        Symbols { decoder: self, in_ }
     }
 
     pub fn decode<R, W>(&mut self, in_: &mut BitReader<R>, out: &mut W) -> io::Result<()>
@@ -280,104 +353,3086 @@ impl DeflateDecoder {
         R: io::Read,
         W: io::Write,
     {
-        while !matches!(self.stage, DecodeStage::Complete) {
-            self.advance_stage(in_, out)?;
+        while let Some(item) = self.next_item(in_)? {
+            if let DecodedItem::Symbol(symbol) = item {
+                self.write_symbol(symbol, out)?;
+            }
         }
 
-        Ok(())
+        out.flush()
     }
 }
 
+/// An iterator over the parts of a Deflate stream, from
+/// `DeflateDecoder::symbols`. It stops after the final block, or after the
+/// first error.
 #[derive(Debug)]
-enum EncodeStage {
-    NewBlock,
-    Complete,
+pub struct Symbols<'a, 'r, R> {
+    decoder: &'a mut DeflateDecoder,
+    in_: &'a mut BitReader<'r, R>,
 }
 
-impl Default for EncodeStage {
-    fn default() -> Self {
-        Self::NewBlock
+impl<R> Iterator for Symbols<'_, '_, R>
+where
+    R: io::Read,
+{
+    type Item = io::Result<DecodedItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.decoder.next_item(self.in_);
+        if item.is_err() {
+            self.decoder.stage = DecodeStage::Complete;
+        }
+
+        item.transpose()
     }
 }
 
-#[derive(Debug, Default)]
-pub struct DeflateEncoder {
-    stage: EncodeStage,
+fn write_block_header<W>(
+    is_final: bool,
+    encoding: DeflateEncoding,
+    out: &mut BitWriter<W>,
+) -> io::Result<()>
+where
+    W: io::Write,
+{
+    out.write_bool(is_final)?;
+    out.write_all(BitVec::from(encoding).as_bitslice())
 }
 
-impl DeflateEncoder {
-    pub fn new() -> Self {
-        Self::default()
+/// Writes the body of a stored block. `bytes` must fit in a single block.
+fn write_stored_block<W>(bytes: &[u8], out: &mut BitWriter<W>) -> io::Result<()>
+where
+    W: io::Write,
+{
+    out.flush_even_if_partial()?;
+
+    let len: u16 = bytes.len().try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("stored blocks hold at most {} bytes", u16::MAX),
+        )
+    })?;
+    out.write_u16(len)?;
+    out.write_u16(!len)?;
+    out.write_bytes(bytes)
+}
+
+/// Counts how often each literal/length code and each distance code is used
+/// in a block holding `symbols`, including its end-of-block symbol.
+fn symbol_frequencies(
+    symbols: &[Symbol],
+) -> ([u32; LENGTH_CODE_COUNT], [u32; DISTANCE_CODE_COUNT]) {
+    let mut literal_frequencies = [0; LENGTH_CODE_COUNT];
+    let mut distance_frequencies = [0; DISTANCE_CODE_COUNT];
+
+    for symbol in symbols.iter().chain([&Symbol::EndOfBlock]) {
+        literal_frequencies[usize::from(symbol.length_code())] += 1;
+
+        if let Symbol::BackReference {
+            distance_minus_one, ..
+        } = *symbol
+        {
+            let distance_code = Symbol::back_reference_distance_code(distance_minus_one);
+            distance_frequencies[usize::from(distance_code)] += 1;
+        }
     }
 
-    fn advance_stage<R, W>(&mut self, in_: &mut R, out: &mut W) -> io::Result<()>
-    where
-        R: io::Read,
-        W: io::Write,
-    {
-        match self.stage {
-            EncodeStage::NewBlock => {
-                const MAX_BYTES_PER_BLOCK: usize = u16::MAX as usize;
-                let mut buf = [0u8; MAX_BYTES_PER_BLOCK];
-                let mut len = 0;
-                let mut is_eof = false;
+    (literal_frequencies, distance_frequencies)
+}
 
-                loop {
-                    match in_.read(&mut buf[len..]) {
-                        Ok(0) => {
-                            is_eof = true;
-                            break;
-                        }
-                        Ok(n) => {
-                            len += n;
-                            if len == MAX_BYTES_PER_BLOCK {
-                                break;
-                            }
-                        }
-                        Err(e) if matches!(e.kind(), io::ErrorKind::Interrupted) => continue,
-                        Err(e) => return Err(e),
-                    }
-                }
+/// Builds the dynamic Huffman codes best suited to `symbols`.
+fn dynamic_codes(symbols: &[Symbol]) -> (HuffmanCode, HuffmanCode) {
+    let (literal_frequencies, distance_frequencies) = symbol_frequencies(symbols);
+    (
+        HuffmanCode::from_frequencies(&literal_frequencies, MAX_CODE_LENGTH),
+        HuffmanCode::from_frequencies(&distance_frequencies, MAX_CODE_LENGTH),
+    )
+}
 
-                let mut header_bits = bitvec![u8, Lsb0; 0; 0];
-                header_bits.push(is_eof);
+/// The cost of each symbol under the given codes. Symbols without a code are
+/// treated as if they had the longest possible one.
+fn symbol_costs(literal_code: &HuffmanCode, distance_code: &HuffmanCode) -> SymbolCosts {
+    let code_costs = |code: &HuffmanCode| {
+        code.code_lengths()
+            .iter()
+            .map(|&code_len| match code_len {
+                0 => MAX_CODE_LENGTH.into(),
+                _ => code_len.into(),
+            })
+            .collect()
+    };
+
+    SymbolCosts {
+        length_code_costs: code_costs(literal_code),
+        distance_code_costs: code_costs(distance_code),
+    }
+}
 
-                let encoding_bits = BitVec::from(DeflateEncoding::NoCompression);
-                header_bits.extend_from_bitslice(encoding_bits.as_bitslice());
+/// The number of bits `symbols` and the end-of-block symbol take up in a
+/// Huffman block using the given codes, not counting the block header.
+fn huffman_block_data_bits(
+    symbols: &[Symbol],
+    literal_code: &HuffmanCode,
+    distance_code: &HuffmanCode,
+) -> u64 {
+    let costs = symbol_costs(literal_code, distance_code);
+    symbols
+        .iter()
+        .chain([&Symbol::EndOfBlock])
+        .map(|&symbol| u64::from(costs.symbol_cost(symbol)))
+        .sum()
+}
 
-                // Pad bits to a full byte
-                header_bits.resize(8, false);
+/// The number of bits `symbols` take up in a dynamic block using the codes
+/// from `dynamic_codes`, including its code lengths header.
+fn dynamic_block_bits(symbols: &[Symbol]) -> u64 {
+    let (literal_code, distance_code) = dynamic_codes(symbols);
+    3 + DynamicHeader::new(&literal_code, &distance_code).bit_count()
+        + huffman_block_data_bits(symbols, &literal_code, &distance_code)
+}
 
-                io::copy(&mut header_bits, out)?;
+/// Trims trailing zero code lengths, keeping at least `min_len` of them.
+fn trim_code_lengths(code_lengths: &[u8], min_len: usize) -> &[u8] {
+    let len = code_lengths
+        .iter()
+        .rposition(|&code_len| code_len > 0)
+        .map_or(0, |idx| idx + 1);
+    &code_lengths[..len.max(min_len)]
+}
 
-                // `.unwrap()` is safe because `len <= u16::MAX`
-                let len_header: u16 = len.try_into().unwrap();
-                let nlen_header = !len_header;
+/// The header of a dynamic block, describing its literal/length and distance
+/// codes.
+#[derive(Debug)]
+struct DynamicHeader {
+    literal_code_length_count: u16,
+    distance_code_length_count: u16,
+    code_lengths_in_symbol_order: Vec<u8>,
+    code_lengths_code: HuffmanCode,
+    code_length_symbols: Vec<CodeLengthSymbol>,
+}
 
-                out.write_all(&len_header.to_le_bytes())?;
-                out.write_all(&nlen_header.to_le_bytes())?;
-                out.write_all(&buf[..len])?;
+impl DynamicHeader {
+    fn new(literal_code: &HuffmanCode, distance_code: &HuffmanCode) -> Self {
+        let literal_code_lengths = trim_code_lengths(literal_code.code_lengths(), 257);
+        let distance_code_lengths = trim_code_lengths(distance_code.code_lengths(), 1);
 
-                if is_eof {
-                    out.flush()?;
-                    self.stage = EncodeStage::Complete;
-                }
+        // Encode each set of code lengths separately, so that no run crosses
+        // from one into the other
+        let mut code_length_symbols = huffman::encode_code_lengths(literal_code_lengths);
+        code_length_symbols.extend(huffman::encode_code_lengths(distance_code_lengths));
 
-                Ok(())
-            }
-            EncodeStage::Complete => Ok(()),
+        // `.unwrap()`s are safe because there are at most 286 literal/length
+        // and 30 distance code lengths
+        Self::with_code_length_symbols(
+            literal_code_lengths.len().try_into().unwrap(),
+            distance_code_lengths.len().try_into().unwrap(),
+            code_length_symbols,
+        )
+    }
+
+    /// Creates a header whose code lengths are run-length encoded as
+    /// `code_length_symbols`.
+    fn with_code_length_symbols(
+        literal_code_length_count: u16,
+        distance_code_length_count: u16,
+        code_length_symbols: Vec<CodeLengthSymbol>,
+    ) -> Self {
+        let mut code_length_frequencies = [0; 19];
+        for code_length_symbol in &code_length_symbols {
+            code_length_frequencies[usize::from(code_length_symbol.symbol)] += 1;
+        }
+
+        let code_lengths_code =
+            HuffmanCode::from_frequencies(&code_length_frequencies, MAX_CODE_LENGTH_CODE_LENGTH);
+        let code_lengths_in_symbol_order =
+            huffman::code_lengths_in_symbol_order(code_lengths_code.code_lengths());
+
+        Self {
+            literal_code_length_count,
+            distance_code_length_count,
+            code_lengths_in_symbol_order,
+            code_lengths_code,
+            code_length_symbols,
         }
     }
 
-    pub fn encode<R, W>(&mut self, in_: &mut R, out: &mut W) -> io::Result<()>
+    fn bit_count(&self) -> u64 {
+        let code_length_symbol_bits: u64 = self
+            .code_length_symbols
+            .iter()
+            .map(|code_length_symbol| {
+                let code_len =
+                    self.code_lengths_code.code_lengths()[usize::from(code_length_symbol.symbol)];
+                u64::from(code_len + code_length_symbol.extra_bit_count())
+            })
+            .sum();
+
+        5 + 5 + 4 + 3 * self.code_lengths_in_symbol_order.len() as u64 + code_length_symbol_bits
+    }
+
+    fn write<W>(&self, out: &mut BitWriter<W>) -> io::Result<()>
     where
-        R: io::Read,
         W: io::Write,
     {
-        while !matches!(self.stage, EncodeStage::Complete) {
-            self.advance_stage(in_, out)?;
+        // `.unwrap()` is safe because there are at most 19 code lengths
+        let code_length_symbol_count: u16 =
+            self.code_lengths_in_symbol_order.len().try_into().unwrap();
+
+        out.write_u16_from_bits(self.literal_code_length_count - 257, 5)?;
+        out.write_u16_from_bits(self.distance_code_length_count - 1, 5)?;
+        out.write_u16_from_bits(code_length_symbol_count - 4, 4)?;
+
+        for &code_length in &self.code_lengths_in_symbol_order {
+            out.write_u16_from_bits(code_length.into(), 3)?;
+        }
+
+        for code_length_symbol in &self.code_length_symbols {
+            self.code_lengths_code
+                .write_symbol(code_length_symbol.symbol.into(), out)?;
+            out.write_u16_from_bits(
+                code_length_symbol.extra_bits.into(),
+                code_length_symbol.extra_bit_count().into(),
+            )?;
         }
 
         Ok(())
     }
 }
+
+/// The number of bits a stored block holding `len` bytes takes up, when
+/// `pending_bit_count` bits of the current byte have already been written.
+fn stored_block_bits(len: usize, pending_bit_count: usize) -> u64 {
+    let header_bits = 3 + pending_bit_count;
+    let padding_bits = (8 - header_bits % 8) % 8;
+    (3 + padding_bits + 32 + 8 * len) as u64
+}
+
+/// The number of bits `symbols` take up in the smallest kind of block, when
+/// `pending_bit_count` bits of the current byte have already been written.
+fn smallest_block_bits(symbols: &[Symbol], pending_bit_count: usize) -> u64 {
+    let fixed_bits = 3 + huffman_block_data_bits(
+        symbols,
+        &HuffmanCode::fixed_literal(),
+        &HuffmanCode::fixed_distance(),
+    );
+    let stored_bits = stored_block_bits(
+        symbols.iter().map(Symbol::decoded_len).sum(),
+        pending_bit_count,
+    );
+
+    dynamic_block_bits(symbols).min(fixed_bits).min(stored_bits)
+}
+
+/// Splits `symbols` into ranges to be written as separate blocks, wherever
+/// giving each side its own Huffman codes makes the output smaller. This
+/// finds the points where the statistics of the data change, such as text
+/// giving way to binary data.
+fn split_block(symbols: &[Symbol], pending_bit_count: usize) -> Vec<Range<usize>> {
+    let mut ranges = vec![];
+    split_range(symbols, 0..symbols.len(), &mut ranges);
+
+    // Split points are chosen assuming each block starts on a byte boundary,
+    // which can make stored blocks a few bits smaller than they really are.
+    // Never write more than the unsplit block, so that the output stays
+    // within `DeflateEncoder::compress_bound`.
+    if ranges.len() > 1 {
+        let mut split_bits = 0;
+        for range in &ranges {
+            let bit_count = (pending_bit_count as u64 + split_bits) % 8;
+            split_bits += smallest_block_bits(&symbols[range.clone()], bit_count as usize);
+        }
+
+        if split_bits >= smallest_block_bits(symbols, pending_bit_count) {
+            return iter::once(0..symbols.len()).collect();
+        }
+    }
+
+    ranges
+}
+
+fn split_range(symbols: &[Symbol], range: Range<usize>, ranges: &mut Vec<Range<usize>>) {
+    /// Smaller blocks rarely make up for the cost of their headers
+    const MIN_SYMBOLS_PER_BLOCK: usize = 256;
+    /// The number of evenly spaced points to try splitting at
+    const SPLIT_CANDIDATES: usize = 16;
+
+    if range.len() < 2 * MIN_SYMBOLS_PER_BLOCK {
+        ranges.push(range);
+        return;
+    }
+
+    let unsplit_bits = smallest_block_bits(&symbols[range.clone()], 0);
+    let (split_bits, mid) = (1..SPLIT_CANDIDATES)
+        .map(|i| range.start + range.len() * i / SPLIT_CANDIDATES)
+        .map(|mid| {
+            let bits = smallest_block_bits(&symbols[range.start..mid], 0)
+                + smallest_block_bits(&symbols[mid..range.end], 0);
+            (bits, mid)
+        })
+        .min()
+        .unwrap();
+
+    if split_bits < unsplit_bits {
+        split_range(symbols, range.start..mid, ranges);
+        split_range(symbols, mid..range.end, ranges);
+    } else {
+        ranges.push(range);
+    }
+}
+
+/// Writes `symbols`, which encode `bytes`, as whichever of a stored, fixed
+/// Huffman or dynamic Huffman block takes up the fewest bits, and returns
+/// what was written.
+fn write_smallest_block<W>(
+    symbols: &[Symbol],
+    bytes: &[u8],
+    is_final: bool,
+    out: &mut BitWriter<W>,
+) -> io::Result<BlockStats>
+where
+    W: io::Write,
+{
+    let frequencies = symbol_frequencies(symbols);
+    let literal_code = HuffmanCode::from_frequencies(&frequencies.0, MAX_CODE_LENGTH);
+    let distance_code = HuffmanCode::from_frequencies(&frequencies.1, MAX_CODE_LENGTH);
+    let dynamic_header = DynamicHeader::new(&literal_code, &distance_code);
+    let dynamic_bits = 3
+        + dynamic_header.bit_count()
+        + huffman_block_data_bits(symbols, &literal_code, &distance_code);
+
+    let fixed_literal_code = HuffmanCode::fixed_literal();
+    let fixed_distance_code = HuffmanCode::fixed_distance();
+    let fixed_bits =
+        3 + huffman_block_data_bits(symbols, &fixed_literal_code, &fixed_distance_code);
+
+    let stored_bits = stored_block_bits(bytes.len(), out.pending_bit_count());
+
+    if stored_bits <= fixed_bits.min(dynamic_bits) {
+        write_block_header(is_final, DeflateEncoding::NoCompression, out)?;
+        write_stored_block(bytes, out)?;
+        Ok(BlockStats::stored(is_final, bytes.len(), stored_bits))
+    } else if fixed_bits <= dynamic_bits {
+        write_block_header(is_final, DeflateEncoding::FixedHuffman, out)?;
+        write_huffman_block(symbols, &fixed_literal_code, &fixed_distance_code, out)?;
+        Ok(BlockStats::huffman(
+            DeflateEncoding::FixedHuffman,
+            is_final,
+            bytes.len(),
+            &frequencies,
+            fixed_bits,
+            None,
+        ))
+    } else {
+        write_block_header(is_final, DeflateEncoding::DynamicHuffman, out)?;
+        dynamic_header.write(out)?;
+        write_huffman_block(symbols, &literal_code, &distance_code, out)?;
+        let code_length_histograms = CodeLengthHistograms::new(&literal_code, &distance_code);
+        Ok(BlockStats::huffman(
+            DeflateEncoding::DynamicHuffman,
+            is_final,
+            bytes.len(),
+            &frequencies,
+            dynamic_bits,
+            Some(code_length_histograms),
+        ))
+    }
+}
+
+/// Writes `symbols` followed by the end-of-block symbol.
+fn write_huffman_block<W>(
+    symbols: &[Symbol],
+    literal_code: &HuffmanCode,
+    distance_code: &HuffmanCode,
+    out: &mut BitWriter<W>,
+) -> io::Result<()>
+where
+    W: io::Write,
+{
+    for symbol in symbols.iter().chain([&Symbol::EndOfBlock]) {
+        write_symbol(symbol, literal_code, distance_code, out)?;
+    }
+
+    Ok(())
+}
+
+fn write_symbol<W>(
+    symbol: &Symbol,
+    literal_code: &HuffmanCode,
+    distance_code: &HuffmanCode,
+    out: &mut BitWriter<W>,
+) -> io::Result<()>
+where
+    W: io::Write,
+{
+    literal_code.write_symbol(symbol.length_code(), out)?;
+
+    if let Symbol::BackReference {
+        length_minus_three,
+        distance_minus_one,
+    } = *symbol
+    {
+        // Each code's base value is a multiple of `2^extra_bit_count`, so the
+        // extra bits are just the low bits of the value.
+        let extra_bit_count = Symbol::back_reference_length_extra_bits(length_minus_three);
+        let extra_bits = u16::from(length_minus_three) & ((1 << extra_bit_count) - 1);
+        out.write_u16_from_bits(extra_bits, extra_bit_count.into())?;
+
+        let distance_symbol = Symbol::back_reference_distance_code(distance_minus_one);
+        distance_code.write_symbol(distance_symbol.into(), out)?;
+
+        let extra_bit_count = Symbol::back_reference_distance_extra_bits(distance_minus_one);
+        let extra_bits = distance_minus_one & ((1 << extra_bit_count) - 1);
+        out.write_u16_from_bits(extra_bits, extra_bit_count.into())?;
+    }
+
+    Ok(())
+}
+
+/// A zlib-style compression level from 0 to 9, trading speed for
+/// compression ratio. Level 0 only writes stored blocks.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct CompressionLevel(u8);
+
+impl CompressionLevel {
+    pub const NONE: Self = Self(0);
+    pub const FASTEST: Self = Self(1);
+    pub const DEFAULT: Self = Self(6);
+    pub const BEST: Self = Self(9);
+
+    pub fn level(self) -> u8 {
+        self.0
+    }
+
+    /// Whether zlib uses its fast, greedy parser, `deflate_fast`, at this
+    /// level.
+    fn is_fast(self) -> bool {
+        (1..=3).contains(&self.0)
+    }
+}
+
+impl Default for CompressionLevel {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl TryFrom<u8> for CompressionLevel {
+    type Error = io::Error;
+
+    fn try_from(level: u8) -> io::Result<Self> {
+        if level > Self::BEST.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("compression level must be <= {}, got {level}", Self::BEST.0),
+            ));
+        }
+
+        Ok(Self(level))
+    }
+}
+
+/// The base-2 logarithm of the encoder's window size, from 9 (512 bytes) to
+/// 15 (32 KiB), like zlib's `windowBits`. Back-references never reach
+/// further back than the window, so a smaller window uses less memory in
+/// both the encoder and the decoder.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct WindowBits(u8);
+
+impl WindowBits {
+    pub const MIN: Self = Self(9);
+    pub const MAX: Self = Self(15);
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn window_size(self) -> usize {
+        1 << self.0
+    }
+}
+
+impl Default for WindowBits {
+    fn default() -> Self {
+        Self::MAX
+    }
+}
+
+impl TryFrom<u8> for WindowBits {
+    type Error = io::Error;
+
+    fn try_from(bits: u8) -> io::Result<Self> {
+        if !(Self::MIN.0..=Self::MAX.0).contains(&bits) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "window bits must be between {} and {}, got {bits}",
+                    Self::MIN.0,
+                    Self::MAX.0
+                ),
+            ));
+        }
+
+        Ok(Self(bits))
+    }
+}
+
+/// How much memory the encoder's match finder uses, from 1 to 9, like
+/// zlib's `memLevel`. The match finder has `2^(level + 7)` hash chains, so
+/// lower levels use less memory but find fewer matches.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct MemLevel(u8);
+
+impl MemLevel {
+    pub const MIN: Self = Self(1);
+    pub const DEFAULT: Self = Self(8);
+    pub const MAX: Self = Self(9);
+
+    pub fn level(self) -> u8 {
+        self.0
+    }
+
+    fn hash_bits(self) -> u32 {
+        u32::from(self.0) + 7
+    }
+}
+
+impl Default for MemLevel {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl TryFrom<u8> for MemLevel {
+    type Error = io::Error;
+
+    fn try_from(level: u8) -> io::Result<Self> {
+        if !(Self::MIN.0..=Self::MAX.0).contains(&level) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "memory level must be between {} and {}, got {level}",
+                    Self::MIN.0,
+                    Self::MAX.0
+                ),
+            ));
+        }
+
+        Ok(Self(level))
+    }
+}
+
+/// Which matches the encoder looks for, like zlib's `strategy` parameter.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Strategy {
+    /// Look for any match in the window.
+    #[default]
+    Default,
+
+    /// Only take matches at least 6 bytes long. Short matches in data such as
+    /// delta-filtered images are mostly coincidental, and cost more than
+    /// entropy coding the bytes.
+    Filtered,
+
+    /// Don't look for matches, and only entropy code the bytes.
+    HuffmanOnly,
+
+    /// Only look for runs of the same byte, which are matches with a
+    /// distance of 1. This is almost as fast as `HuffmanOnly`.
+    Rle,
+}
+
+impl Strategy {
+    /// The shortest match worth taking.
+    fn min_match_length(self) -> usize {
+        match self {
+            Self::Filtered => 6,
+            Self::Default | Self::HuffmanOnly | Self::Rle => lzss::MIN_MATCH_LENGTH,
+        }
+    }
+}
+
+/// Options for `DeflateEncoder`. These default to the settings for the
+/// chosen compression level, and mirror the parameters of zlib's
+/// `deflateTune`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EncoderOptions {
+    pub level: CompressionLevel,
+    pub strategy: Strategy,
+
+    /// Once a match at least this long is found, search a quarter as many
+    /// positions when looking for a longer match at the next position.
+    pub good_length: usize,
+
+    /// Only look for a longer match at the next position (lazy matching) if
+    /// the current match is shorter than this. A value of 0 disables lazy
+    /// matching and takes every match immediately.
+    ///
+    /// Levels 1 to 3 never match lazily, like zlib's `deflate_fast`. There,
+    /// the positions inside matches longer than this aren't indexed instead,
+    /// to save time.
+    pub max_lazy_length: usize,
+
+    /// Stop searching once a match at least this long is found.
+    pub nice_length: usize,
+
+    /// The most earlier positions to search for a match.
+    pub max_chain_length: usize,
+
+    /// When nonzero, parse each block by searching for its cheapest encoding
+    /// under the Huffman codes of the previous parse, this many times. This
+    /// is much slower, but gives the best compression. It has no effect with
+    /// the `HuffmanOnly` and `Rle` strategies.
+    pub optimal_parse_iterations: usize,
+
+    /// Split the input into blocks where its statistics change, so that each
+    /// part gets Huffman codes suited to it.
+    pub split_blocks: bool,
+
+    pub window_bits: WindowBits,
+    pub mem_level: MemLevel,
+
+    /// Decode the output on another thread as it is written, and return an
+    /// error when encoding finishes if it doesn't decode back to the input.
+    pub verify: bool,
+
+    /// Like `gzip --rsyncable`, do a full flush wherever a rolling hash of the
+    /// input hits a certain value, so that a local change to the input only
+    /// changes the output nearby. This makes the output slightly larger.
+    pub rsyncable: bool,
+}
+
+impl From<CompressionLevel> for EncoderOptions {
+    /// Follows zlib's `configuration_table`.
+    fn from(level: CompressionLevel) -> Self {
+        let (good_length, max_lazy_length, nice_length, max_chain_length) = match level.0 {
+            0 => (0, 0, 0, 0),
+            1 => (4, 4, 8, 4),
+            2 => (4, 5, 16, 8),
+            3 => (4, 6, 32, 32),
+            4 => (4, 4, 16, 16),
+            5 => (8, 16, 32, 32),
+            6 => (8, 16, 128, 128),
+            7 => (8, 32, 128, 256),
+            8 => (32, 128, 258, 1024),
+            9.. => (32, 258, 258, 4096),
+        };
+
+        Self {
+            level,
+            strategy: Strategy::default(),
+            good_length,
+            max_lazy_length,
+            nice_length,
+            max_chain_length,
+            optimal_parse_iterations: 0,
+            split_blocks: false,
+            window_bits: WindowBits::default(),
+            mem_level: MemLevel::default(),
+            verify: false,
+            rsyncable: false,
+        }
+    }
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        CompressionLevel::default().into()
+    }
+}
+
+/// Statistics about the blocks an encoder has written, to show how well each
+/// part of the input compressed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompressionStats {
+    /// Every block written so far, in order
+    pub blocks: Vec<BlockStats>,
+}
+
+impl CompressionStats {
+    /// The number of bytes of input encoded so far.
+    pub fn input_bytes(&self) -> usize {
+        self.blocks.iter().map(|block| block.input_bytes).sum()
+    }
+
+    /// The size of all the blocks written so far. This doesn't count the
+    /// padding after the final block.
+    pub fn output_bits(&self) -> u64 {
+        self.blocks.iter().map(|block| block.output_bits).sum()
+    }
+}
+
+/// Statistics about one block of encoded output.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlockStats {
+    pub encoding: DeflateEncoding,
+    pub is_final: bool,
+
+    /// The number of bytes of input the block encodes.
+    pub input_bytes: usize,
+
+    /// The size of the block, including its header and any padding before
+    /// the contents of a stored block.
+    pub output_bits: u64,
+
+    /// The number of literals. Each byte of a stored block counts as one.
+    pub literal_count: usize,
+
+    /// The number of back-references.
+    pub match_count: usize,
+
+    /// How many back-references use each length code, indexed by the code
+    /// minus 257. Each code covers a range of lengths, as listed in
+    /// RFC 1951, section 3.2.5.
+    pub match_length_histogram: [u32; LENGTH_CODE_COUNT - 257],
+
+    /// How many back-references use each distance code. Each code covers a
+    /// range of distances, as listed in RFC 1951, section 3.2.5.
+    pub match_distance_histogram: [u32; DISTANCE_CODE_COUNT],
+
+    /// For dynamic Huffman blocks, how long the codes in the header are.
+    pub code_length_histograms: Option<CodeLengthHistograms>,
+}
+
+impl BlockStats {
+    fn stored(is_final: bool, len: usize, output_bits: u64) -> Self {
+        Self {
+            encoding: DeflateEncoding::NoCompression,
+            is_final,
+            input_bytes: len,
+            output_bits,
+            literal_count: len,
+            match_count: 0,
+            match_length_histogram: [0; LENGTH_CODE_COUNT - 257],
+            match_distance_histogram: [0; DISTANCE_CODE_COUNT],
+            code_length_histograms: None,
+        }
+    }
+
+    /// `frequencies` are the block's symbol frequencies, as returned by
+    /// `symbol_frequencies`.
+    fn huffman(
+        encoding: DeflateEncoding,
+        is_final: bool,
+        len: usize,
+        frequencies: &([u32; LENGTH_CODE_COUNT], [u32; DISTANCE_CODE_COUNT]),
+        output_bits: u64,
+        code_length_histograms: Option<CodeLengthHistograms>,
+    ) -> Self {
+        let (literal_frequencies, distance_frequencies) = frequencies;
+        let mut match_length_histogram = [0; LENGTH_CODE_COUNT - 257];
+        match_length_histogram.copy_from_slice(&literal_frequencies[257..]);
+
+        Self {
+            encoding,
+            is_final,
+            input_bytes: len,
+            output_bits,
+            literal_count: literal_frequencies[..256].iter().sum::<u32>() as usize,
+            match_count: match_length_histogram.iter().sum::<u32>() as usize,
+            match_length_histogram,
+            match_distance_histogram: *distance_frequencies,
+            code_length_histograms,
+        }
+    }
+}
+
+/// How many codes of a dynamic Huffman block have each length, indexed by
+/// length. Index 0 counts the symbols that have no code.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CodeLengthHistograms {
+    /// For the literal/length code
+    pub literal_length: [u32; MAX_CODE_LENGTH as usize + 1],
+    /// For the distance code
+    pub distance: [u32; MAX_CODE_LENGTH as usize + 1],
+}
+
+impl CodeLengthHistograms {
+    fn new(literal_code: &HuffmanCode, distance_code: &HuffmanCode) -> Self {
+        let histogram = |code: &HuffmanCode| {
+            let mut histogram = [0; MAX_CODE_LENGTH as usize + 1];
+            for &code_len in code.code_lengths() {
+                histogram[usize::from(code_len)] += 1;
+            }
+            histogram
+        };
+
+        Self {
+            literal_length: histogram(literal_code),
+            distance: histogram(distance_code),
+        }
+    }
+}
+
+/// A rolling hash of the last few bytes of input, which picks the points
+/// where `rsyncable` output is flushed. This is the hash that pigz uses.
+#[derive(Debug, Default)]
+pub struct RsyncHash {
+    hash: u32,
+    /// The number of bytes since the last point
+    len: usize,
+}
+
+impl RsyncHash {
+    const MASK: u32 = (1 << RSYNC_HASH_BITS) - 1;
+    /// The hash at each point
+    const POINT_HASH: u32 = Self::MASK >> 1;
+
+    /// Adds the next byte of input, and returns `true` if the output should
+    /// be flushed after it.
+    pub fn update(&mut self, byte: u8) -> bool {
+        self.hash = ((self.hash << 1) ^ u32::from(byte)) & Self::MASK;
+        self.len += 1;
+
+        let is_point = self.hash == Self::POINT_HASH && self.len >= RSYNC_MIN_LEN;
+        if is_point {
+            self.len = 0;
+        }
+        is_point
+    }
+
+    /// Goes back to the state right after a point.
+    fn rewind_to_point(&mut self) {
+        self.hash = Self::POINT_HASH;
+        self.len = 0;
+    }
+}
+
+#[derive(Debug, Default)]
+enum EncodeStage {
+    #[default]
+    NewBlock,
+    Complete,
+}
+
+/// Preset dictionaries can be at most as long as the window, since nothing
+/// before that can be reached.
+fn check_dictionary_len(dictionary: &[u8], window_size: usize) -> io::Result<()> {
+    if dictionary.len() > window_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "dictionary length {} is greater than the window size {window_size}",
+                dictionary.len()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+impl From<EncoderOptions> for HashChain {
+    fn from(options: EncoderOptions) -> Self {
+        Self::new(
+            options.mem_level.hash_bits(),
+            options.window_bits.window_size(),
+            options.strategy.min_match_length(),
+            options.max_chain_length,
+            options.nice_length,
+        )
+    }
+}
+
+impl From<EncoderOptions> for BinaryTree {
+    fn from(options: EncoderOptions) -> Self {
+        Self::new(
+            options.mem_level.hash_bits(),
+            options.window_bits.window_size(),
+            options.strategy.min_match_length(),
+            options.max_chain_length,
+            options.nice_length,
+        )
+    }
+}
+
+/// Encodes data as a Deflate stream, finding matches with `M`.
+#[derive(Debug)]
+pub struct DeflateEncoder<M = HashChain> {
+    /// Up to 32k of already-encoded input, followed by input that is waiting
+    /// to be encoded
+    window: Vec<u8>,
+    /// The length of the already-encoded part of `window`
+    history_len: usize,
+    options: EncoderOptions,
+    match_finder: M,
+    stage: EncodeStage,
+    /// Checks the output when `options.verify` is set
+    verifier: Option<Verifier>,
+    /// Picks where to flush when `options.rsyncable` is set
+    rsync_hash: RsyncHash,
+    stats: CompressionStats,
+}
+
+impl Default for DeflateEncoder {
+    fn default() -> Self {
+        Self::with_level(CompressionLevel::default())
+    }
+}
+
+impl DeflateEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_level(level: CompressionLevel) -> Self {
+        Self::with_options(level.into())
+    }
+
+    pub fn with_options(options: EncoderOptions) -> Self {
+        Self::with_match_finder(options, options.into())
+    }
+}
+
+impl<M> DeflateEncoder<M>
+where
+    M: MatchFinder,
+{
+    /// Creates an encoder that finds matches with `match_finder`, instead of
+    /// a `HashChain` built from `options`. Options that only configure the
+    /// match finder, such as `max_chain_length`, are ignored, but its window
+    /// can't be larger than `options.window_bits` allows.
+    pub fn with_match_finder(options: EncoderOptions, match_finder: M) -> Self {
+        assert!(match_finder.window_size() <= options.window_bits.window_size());
+
+        Self {
+            window: vec![],
+            history_len: 0,
+            options,
+            match_finder,
+            stage: EncodeStage::default(),
+            verifier: None,
+            rsync_hash: RsyncHash::default(),
+            stats: CompressionStats::default(),
+        }
+    }
+
+    /// Creates an encoder whose back-references can reach into a preset
+    /// dictionary, as if it came right before the input. The same dictionary
+    /// must be passed to `DeflateDecoder::with_dictionary` to decode. The
+    /// match finder is built from `options`.
+    pub fn with_dictionary(options: EncoderOptions, dictionary: &[u8]) -> io::Result<Self>
+    where
+        M: From<EncoderOptions>,
+    {
+        Self::with_match_finder(options, options.into()).prime_dictionary(dictionary)
+    }
+
+    /// Encodes later blocks with `level` and `strategy`. Like zlib's
+    /// `deflateParams`, the options that tune the level are reset to its
+    /// defaults, while the window and memory settings are kept, along with
+    /// the options that aren't part of a level, such as `split_blocks`. The
+    /// match finder is rebuilt from the new options.
+    fn set_params(&mut self, level: CompressionLevel, strategy: Strategy)
+    where
+        M: From<EncoderOptions>,
+    {
+        let EncoderOptions {
+            window_bits,
+            mem_level,
+            split_blocks,
+            optimal_parse_iterations,
+            verify,
+            rsyncable,
+            ..
+        } = self.options;
+
+        self.options = EncoderOptions {
+            strategy,
+            window_bits,
+            mem_level,
+            split_blocks,
+            optimal_parse_iterations,
+            verify,
+            rsyncable,
+            ..level.into()
+        };
+        self.match_finder = self.options.into();
+    }
+
+    /// Makes `dictionary` the history that back-references can reach into.
+    fn prime_dictionary(mut self, dictionary: &[u8]) -> io::Result<Self> {
+        check_dictionary_len(dictionary, self.options.window_bits.window_size())?;
+
+        self.window.extend_from_slice(dictionary);
+        self.history_len = dictionary.len();
+
+        Ok(self)
+    }
+
+    /// Statistics about the blocks written so far.
+    pub fn stats(&self) -> &CompressionStats {
+        &self.stats
+    }
+
+    /// Wraps the writer that the encoded stream is written to, so that it can
+    /// be checked if `options.verify` is set.
+    fn verified_out<W>(&mut self, out: W) -> VerifiedOut<W> {
+        if self.options.verify && self.verifier.is_none() {
+            // The history so far is the preset dictionary, if there is one
+            self.verifier = Some(Verifier::new(&self.window[..self.history_len]));
+        }
+
+        VerifiedOut::new(out, self.verifier.as_ref().and_then(Verifier::sender))
+    }
+
+    /// Checks that everything written to `verified_out` decodes back to the
+    /// input. This must be called after the `VerifiedOut` has been dropped.
+    fn finish_verifying(&mut self) -> io::Result<()> {
+        match self.verifier.take() {
+            Some(verifier) => verifier.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes verification fail, as if the output had lost a byte on its way
+    /// to the decoder. `options.verify` must be set.
+    #[cfg(test)]
+    pub fn corrupt_verification(&mut self) {
+        assert!(self.options.verify);
+        let dictionary = &self.window[..self.history_len];
+        self.verifier
+            .get_or_insert_with(|| Verifier::new(dictionary))
+            .write_input(b"\0");
+    }
+
+    /// Parses `window[start..end]` into symbols.
+    fn parse_block(&mut self, start: usize, end: usize) -> Vec<Symbol> {
+        let data = &self.window[..end];
+        let EncoderOptions {
+            level,
+            strategy,
+            good_length,
+            max_lazy_length,
+            optimal_parse_iterations,
+            ..
+        } = self.options;
+
+        match strategy {
+            Strategy::Default | Strategy::Filtered => {}
+            Strategy::HuffmanOnly => {
+                return data[start..]
+                    .iter()
+                    .map(|&byte| Symbol::Literal(byte))
+                    .collect();
+            }
+            Strategy::Rle => return lzss::rle_parse(data, start),
+        }
+
+        let mut symbols = if level.is_fast() {
+            // Like zlib's fast levels, skip indexing the inside of long matches
+            lzss::greedy_parse(&mut self.match_finder, data, start, max_lazy_length)
+        } else {
+            lzss::lazy_parse(
+                &mut self.match_finder,
+                data,
+                start,
+                good_length,
+                max_lazy_length,
+            )
+        };
+
+        if optimal_parse_iterations > 0 {
+            symbols = self.optimal_parse(start, end, symbols);
+        }
+
+        symbols
+    }
+
+    /// Repeatedly finds the cheapest parse of `window[start..end]` under the
+    /// Huffman codes of the previous parse, starting from `symbols`. Returns
+    /// the parse that encodes to the fewest bits.
+    fn optimal_parse(&mut self, start: usize, end: usize, symbols: Vec<Symbol>) -> Vec<Symbol> {
+        let data = &self.window[..end];
+        let matches = lzss::find_all_matches(&mut self.match_finder, data, start);
+
+        let mut best_bits = dynamic_block_bits(&symbols);
+        let mut best_symbols = symbols;
+        let mut prev_symbols = best_symbols.clone();
+
+        for _ in 0..self.options.optimal_parse_iterations {
+            let (literal_code, distance_code) = dynamic_codes(&prev_symbols);
+            let costs = symbol_costs(&literal_code, &distance_code);
+
+            let symbols = lzss::optimal_parse(data, start, &matches, &costs);
+
+            let bits = dynamic_block_bits(&symbols);
+            if bits < best_bits {
+                best_bits = bits;
+                best_symbols.clone_from(&symbols);
+            }
+            prev_symbols = symbols;
+        }
+
+        best_symbols
+    }
+
+    /// Returns an error if the final block has already been written, since
+    /// anything written after it isn't part of the stream.
+    fn check_not_complete(&self) -> io::Result<()> {
+        if matches!(self.stage, EncodeStage::Complete) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the stream has already been finished",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The number of input bytes waiting to be encoded.
+    fn pending_len(&self) -> usize {
+        self.window.len() - self.history_len
+    }
+
+    /// Encodes the next `len` bytes of pending input, which must fit in a
+    /// stored block, as one or more blocks.
+    ///
+    /// With `options.rsyncable`, this also does a full flush at each point
+    /// the rolling hash picks. Unless this is the final block, the input
+    /// after the last point is left pending, so that the next blocks start
+    /// at that point.
+    fn write_blocks<W>(
+        &mut self,
+        len: usize,
+        is_final: bool,
+        out: &mut BitWriter<W>,
+    ) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        if !self.options.rsyncable {
+            return self.write_blocks_unflushed(len, is_final, out);
+        }
+
+        let mut remaining_len = len;
+        let mut has_flushed = false;
+        while let Some(point) = self.next_rsync_point(remaining_len) {
+            self.write_blocks_unflushed(point, false, out)?;
+            self.write_stored(0, false, out)?;
+            self.reset_window();
+
+            remaining_len -= point;
+            has_flushed = true;
+        }
+
+        if is_final || !has_flushed {
+            self.write_blocks_unflushed(remaining_len, is_final, out)
+        } else {
+            // The rest is hashed again when it is encoded
+            self.rsync_hash.rewind_to_point();
+            Ok(())
+        }
+    }
+
+    /// Hashes up to `len` bytes of pending input, and returns how many of
+    /// them come before the next rsync point, if there is one.
+    fn next_rsync_point(&mut self, len: usize) -> Option<usize> {
+        let start = self.history_len;
+        let bytes = &self.window[start..start + len];
+        bytes
+            .iter()
+            .position(|&byte| self.rsync_hash.update(byte))
+            .map(|pos| pos + 1)
+    }
+
+    /// Like `write_blocks`, without any rsync flushes.
+    fn write_blocks_unflushed<W>(
+        &mut self,
+        len: usize,
+        is_final: bool,
+        out: &mut BitWriter<W>,
+    ) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        let start = self.history_len;
+        let end = start + len;
+
+        if self.options.level == CompressionLevel::NONE {
+            self.write_stored(len, is_final, out)
+        } else {
+            let symbols = self.parse_block(start, end);
+            self.write_symbols(&symbols, is_final, out)
+        }
+    }
+
+    /// Writes `symbols`, which encode the next bytes of pending input, as one
+    /// or more blocks.
+    fn write_symbols<W>(
+        &mut self,
+        symbols: &[Symbol],
+        is_final: bool,
+        out: &mut BitWriter<W>,
+    ) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        let ranges = if self.options.split_blocks {
+            split_block(symbols, out.pending_bit_count())
+        } else {
+            iter::once(0..symbols.len()).collect()
+        };
+
+        let start = self.history_len;
+        let mut block_start = start;
+        for (idx, range) in ranges.iter().enumerate() {
+            let block_symbols = &symbols[range.clone()];
+            let block_len: usize = block_symbols.iter().map(Symbol::decoded_len).sum();
+            let block_end = block_start + block_len;
+
+            let block_stats = write_smallest_block(
+                block_symbols,
+                &self.window[block_start..block_end],
+                is_final && idx == ranges.len() - 1,
+                out,
+            )?;
+            self.stats.blocks.push(block_stats);
+            block_start = block_end;
+        }
+
+        self.advance_history(block_start - start);
+        Ok(())
+    }
+
+    /// Writes the next `len` bytes of pending input as a stored block.
+    fn write_stored<W>(
+        &mut self,
+        len: usize,
+        is_final: bool,
+        out: &mut BitWriter<W>,
+    ) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        let start = self.history_len;
+        let output_bits = stored_block_bits(len, out.pending_bit_count());
+        write_block_header(is_final, DeflateEncoding::NoCompression, out)?;
+        write_stored_block(&self.window[start..start + len], out)?;
+
+        self.stats
+            .blocks
+            .push(BlockStats::stored(is_final, len, output_bits));
+        self.advance_history(len);
+        Ok(())
+    }
+
+    /// Marks the next `len` bytes of pending input as encoded.
+    fn advance_history(&mut self, len: usize) {
+        let end = self.history_len + len;
+        if let Some(verifier) = &mut self.verifier {
+            verifier.write_input(&self.window[self.history_len..end]);
+        }
+
+        // Only keep what later back-references can reach
+        let history_start = end.saturating_sub(self.options.window_bits.window_size());
+        self.window.drain(..history_start);
+        self.history_len = end - history_start;
+    }
+
+    /// Appends the bytes `symbol` decodes to onto the pending input, checking
+    /// that a back-reference only reaches into the window.
+    fn push_symbol(&mut self, symbol: Symbol) -> io::Result<()> {
+        match symbol {
+            Symbol::Literal(byte) => self.window.push(byte),
+            Symbol::EndOfBlock => {}
+            Symbol::BackReference {
+                distance_minus_one, ..
+            } => {
+                let distance = usize::from(distance_minus_one) + 1;
+                let max_distance = self
+                    .window
+                    .len()
+                    .min(self.options.window_bits.window_size());
+                if distance > max_distance {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "back-reference distance {distance} is greater than {max_distance}"
+                        ),
+                    ));
+                }
+
+                let start = self.window.len() - distance;
+                for idx in start..start + symbol.decoded_len() {
+                    self.window.push(self.window[idx]);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encodes all pending input, then writes an empty stored block so that
+    /// the output so far ends on a byte boundary.
+    fn write_sync_flush<W>(&mut self, out: &mut BitWriter<W>) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        while self.pending_len() > 0 {
+            self.write_blocks(self.pending_len().min(MAX_BYTES_PER_BLOCK), false, out)?;
+        }
+
+        // An empty stored block ends the output on a byte boundary
+        self.write_stored(0, false, out)
+    }
+
+    /// Forgets all encoded input, so that later back-references can't reach
+    /// it.
+    fn reset_window(&mut self) {
+        self.window.drain(..self.history_len);
+        self.history_len = 0;
+    }
+
+    fn advance_stage<R, W>(&mut self, in_: &mut R, out: &mut BitWriter<W>) -> io::Result<()>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        match self.stage {
+            EncodeStage::NewBlock => {
+                // Reading one byte more than a block holds shows whether a
+                // full block is the last one, so that it can be final
+                let read_len = MAX_BYTES_PER_BLOCK + 1 - self.pending_len();
+                let start = self.window.len();
+                self.window.resize(start + read_len, 0);
+                let buf = &mut self.window[start..];
+                let mut len = 0;
+                let mut is_eof = false;
+
+                loop {
+                    match in_.read(&mut buf[len..]) {
+                        Ok(0) => {
+                            is_eof = true;
+                            break;
+                        }
+                        Ok(n) => {
+                            len += n;
+                            if len == read_len {
+                                break;
+                            }
+                        }
+                        Err(e) if matches!(e.kind(), io::ErrorKind::Interrupted) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                self.window.truncate(start + len);
+
+                if is_eof {
+                    while self.pending_len() > MAX_BYTES_PER_BLOCK {
+                        self.write_blocks(MAX_BYTES_PER_BLOCK, false, out)?;
+                    }
+                    self.write_blocks(self.pending_len(), true, out)?;
+                    out.flush_even_if_partial()?;
+                    self.stage = EncodeStage::Complete;
+                } else {
+                    // At least one byte is kept back for the final block.
+                    // Rsync flushes can leave more input pending.
+                    while self.pending_len() > MAX_BYTES_PER_BLOCK {
+                        self.write_blocks(MAX_BYTES_PER_BLOCK, false, out)?;
+                    }
+                }
+
+                Ok(())
+            }
+            EncodeStage::Complete => Ok(()),
+        }
+    }
+
+    /// The most bytes `encode` can write for `input_len` bytes of input,
+    /// like zlib's `deflateBound`.
+    ///
+    /// Every block is written in whichever form is smallest, so the output is
+    /// never larger than storing the input in blocks of up to 65535 bytes,
+    /// each with 5 bytes of header. With `rsyncable`, each rsync point adds up
+    /// to 10 more bytes: 5 for the empty stored block of its flush, and 5 for
+    /// the header of the block that it splits off. Points are at least
+    /// `RSYNC_MIN_LEN` bytes apart.
+    ///
+    /// `DeflateWriter` can write up to 10 more bytes for each `flush_sync` or
+    /// `flush_full`: 5 for the empty stored block, and 5 for the header of
+    /// the extra block that ending the current one early can lead to. Each
+    /// `set_params` can add 5 bytes in the same way.
+    pub fn compress_bound(&self, input_len: usize) -> usize {
+        let block_count = input_len.div_ceil(MAX_BYTES_PER_BLOCK).max(1);
+        let rsync_point_count = if self.options.rsyncable {
+            input_len / RSYNC_MIN_LEN + 1
+        } else {
+            0
+        };
+        input_len + 5 * block_count + 10 * rsync_point_count
+    }
+
+    /// Encodes as much of `input` as fits in `output` as a complete Deflate
+    /// stream, and returns how many bytes of input were encoded and how many
+    /// bytes of output were written.
+    ///
+    /// `output` must hold at least 2 bytes, enough for an empty stream. Like
+    /// `encode_symbols`, this returns an error if the stream has already been
+    /// finished.
+    pub fn encode_bounded(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> io::Result<(usize, usize)> {
+        /// A fixed Huffman block holding only the end-of-block symbol
+        const EMPTY_BLOCK_BITS: u64 = 10;
+
+        self.check_not_complete()?;
+        if output.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("output must hold at least 2 bytes, got {}", output.len()),
+            ));
+        }
+
+        let capacity = output.len();
+        let capacity_bits = 8 * capacity as u64;
+        let mut out = BitWriter::new(self.verified_out(output));
+        let mut consumed = 0;
+
+        let mut chunks = input.chunks(MAX_BYTES_PER_BLOCK).peekable();
+        while let Some(chunk) = chunks.next() {
+            let start = self.window.len();
+            self.window.extend_from_slice(chunk);
+            let symbols = self.parse_block(start, self.window.len());
+
+            let written_bits = 8 * (capacity - out.get_ref().get_ref().len()) as u64;
+            let pending_bit_count = out.pending_bit_count();
+            let fits = |symbols: &[Symbol], reserved_bits| {
+                written_bits
+                    + pending_bit_count as u64
+                    + self.bounded_block_bits(symbols, pending_bit_count)
+                    + reserved_bits
+                    <= capacity_bits
+            };
+
+            // Leave room for at least an empty final block
+            if chunks.peek().is_some() && fits(&symbols, EMPTY_BLOCK_BITS) {
+                self.write_symbol_block(&symbols, false, &mut out)?;
+                consumed += chunk.len();
+                continue;
+            }
+
+            // Find the most symbols that fit in the final block. Blocks
+            // mostly get larger as symbols are added, so a binary search gets
+            // close to the most that fit.
+            let (mut low, mut high) = (0, symbols.len());
+            while low < high {
+                let mid = (low + high).div_ceil(2);
+                if fits(&symbols[..mid], 0) {
+                    low = mid;
+                } else {
+                    high = mid - 1;
+                }
+            }
+
+            let symbols = &symbols[..low];
+            let len: usize = symbols.iter().map(Symbol::decoded_len).sum();
+            self.window.truncate(start + len);
+            self.write_symbol_block(symbols, true, &mut out)?;
+            consumed += len;
+            break;
+        }
+
+        if input.is_empty() {
+            self.write_symbol_block(&[], true, &mut out)?;
+        }
+
+        out.flush_even_if_partial()?;
+        self.stage = EncodeStage::Complete;
+
+        let written = capacity - out.into_inner().into_inner().len();
+        self.finish_verifying()?;
+        Ok((consumed, written))
+    }
+
+    /// Whether `write_symbol_block` writes `symbols` as a stored block. An
+    /// empty block is smaller as a fixed Huffman block.
+    fn is_symbol_block_stored(&self, symbols: &[Symbol]) -> bool {
+        self.options.level == CompressionLevel::NONE && !symbols.is_empty()
+    }
+
+    /// The number of bits `encode_bounded` writes for a block of `symbols`,
+    /// when `pending_bit_count` bits of the current byte have already been
+    /// written. Block splitting only ever makes this smaller.
+    fn bounded_block_bits(&self, symbols: &[Symbol], pending_bit_count: usize) -> u64 {
+        if self.is_symbol_block_stored(symbols) {
+            let len = symbols.iter().map(Symbol::decoded_len).sum();
+            stored_block_bits(len, pending_bit_count)
+        } else {
+            smallest_block_bits(symbols, pending_bit_count)
+        }
+    }
+
+    /// Writes `symbols`, which encode the next bytes of pending input. At
+    /// `CompressionLevel::NONE`, they are stored instead.
+    fn write_symbol_block<W>(
+        &mut self,
+        symbols: &[Symbol],
+        is_final: bool,
+        out: &mut BitWriter<W>,
+    ) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        if self.is_symbol_block_stored(symbols) {
+            let len = symbols.iter().map(Symbol::decoded_len).sum();
+            self.write_stored(len, is_final, out)
+        } else {
+            self.write_symbols(symbols, is_final, out)
+        }
+    }
+
+    /// Encodes `symbols`, such as from a custom parser, instead of parsing
+    /// input. Blocks end wherever a `Symbol::EndOfBlock` appears, and
+    /// otherwise whenever they get too large. At `CompressionLevel::NONE`,
+    /// the blocks are stored instead.
+    ///
+    /// Back-references can't reach further back than the window, or before
+    /// the start of the data and the preset dictionary. An invalid
+    /// back-reference returns an error, after everything before it has been
+    /// written, though without a final block. So does calling this after the
+    /// stream has been finished.
+    pub fn encode_symbols<I, W>(&mut self, symbols: I, out: &mut W) -> io::Result<()>
+    where
+        I: IntoIterator<Item = Symbol>,
+        W: io::Write,
+    {
+        self.check_not_complete()?;
+        let mut bit_out = BitWriter::new(self.verified_out(&mut *out));
+        let mut block = vec![];
+        let mut symbols = symbols.into_iter().peekable();
+
+        while let Some(symbol) = symbols.next() {
+            if symbol == Symbol::EndOfBlock {
+                // The last block is written below, with `BFINAL` set
+                if !block.is_empty() && symbols.peek().is_some() {
+                    self.write_symbol_block(&block, false, &mut bit_out)?;
+                    block.clear();
+                }
+                continue;
+            }
+
+            // Keep every block small enough to be stored
+            if self.pending_len() + symbol.decoded_len() > MAX_BYTES_PER_BLOCK {
+                self.write_symbol_block(&block, false, &mut bit_out)?;
+                block.clear();
+            }
+
+            if let Err(e) = self.push_symbol(symbol) {
+                if !block.is_empty() {
+                    self.write_symbol_block(&block, false, &mut bit_out)?;
+                }
+                bit_out.flush_even_if_partial()?;
+                drop(bit_out);
+                out.flush()?;
+                return Err(e);
+            }
+            block.push(symbol);
+        }
+
+        self.write_symbol_block(&block, true, &mut bit_out)?;
+        bit_out.flush_even_if_partial()?;
+        self.stage = EncodeStage::Complete;
+        drop(bit_out);
+
+        out.flush()?;
+        self.finish_verifying()
+    }
+
+    /// Encodes everything read from `in_`. The output only depends on the
+    /// input bytes and the options, not on how `in_` splits them between
+    /// reads, and is the same as from `DeflateWriter` and `DeflateReader`.
+    /// Each encoder only writes one stream, so calling this again returns an
+    /// error.
+    pub fn encode<R, W>(&mut self, in_: &mut R, out: &mut W) -> io::Result<()>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        self.check_not_complete()?;
+        let mut bit_out = BitWriter::new(self.verified_out(&mut *out));
+        while !matches!(self.stage, EncodeStage::Complete) {
+            self.advance_stage(in_, &mut bit_out)?;
+        }
+        drop(bit_out);
+
+        out.flush()?;
+        self.finish_verifying()
+    }
+}
+
+/// Encodes `chunk`, one part of a larger input, as if `dictionary` were the
+/// input that came before it. Unless this is the last chunk, the output ends
+/// with a sync flush, so the next chunk's output can be appended to it to
+/// make one stream.
+pub fn encode_chunk(
+    options: EncoderOptions,
+    dictionary: &[u8],
+    chunk: &[u8],
+    is_last: bool,
+) -> io::Result<Vec<u8>> {
+    let mut encoder: DeflateEncoder = DeflateEncoder::with_dictionary(options, dictionary)?;
+    let mut out = BitWriter::new(vec![]);
+    encoder.window.extend_from_slice(chunk);
+
+    if is_last {
+        while encoder.pending_len() > MAX_BYTES_PER_BLOCK {
+            encoder.write_blocks(MAX_BYTES_PER_BLOCK, false, &mut out)?;
+        }
+        encoder.write_blocks(encoder.pending_len(), true, &mut out)?;
+        out.flush_even_if_partial()?;
+    } else {
+        encoder.write_sync_flush(&mut out)?;
+    }
+
+    Ok(out.into_inner())
+}
+
+/// A writer that compresses everything written to it into a Deflate stream,
+/// written to an inner writer.
+///
+/// Input is buffered until a full block is available. `finish` must be
+/// called to write the final block.
+///
+/// Blocks only end after a fixed amount of input, or where a flush or
+/// `set_params` asks for it, so the output doesn't depend on how the input
+/// is split between calls to `write`.
+#[derive(Debug)]
+pub struct DeflateWriter<W, M = HashChain> {
+    encoder: DeflateEncoder<M>,
+    out: BitWriter<VerifiedOut<W>>,
+}
+
+impl<W> DeflateWriter<W>
+where
+    W: io::Write,
+{
+    pub fn new(inner: W) -> Self {
+        Self::with_options(inner, EncoderOptions::default())
+    }
+
+    pub fn with_options(inner: W, options: EncoderOptions) -> Self {
+        Self::with_encoder(inner, DeflateEncoder::with_options(options))
+    }
+
+    /// Creates a writer that encodes with a preset dictionary. See
+    /// `DeflateEncoder::with_dictionary`.
+    pub fn with_dictionary(
+        inner: W,
+        options: EncoderOptions,
+        dictionary: &[u8],
+    ) -> io::Result<Self> {
+        Ok(Self::with_encoder(
+            inner,
+            DeflateEncoder::with_dictionary(options, dictionary)?,
+        ))
+    }
+}
+
+impl<W, M> DeflateWriter<W, M>
+where
+    W: io::Write,
+    M: MatchFinder,
+{
+    /// Creates a writer that encodes with `encoder`, such as one that finds
+    /// matches with a `BinaryTree`. The encoder must not have been used yet.
+    pub fn with_encoder(inner: W, mut encoder: DeflateEncoder<M>) -> Self {
+        let out = BitWriter::new(encoder.verified_out(inner));
+        Self { encoder, out }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.out.get_ref().get_ref()
+    }
+
+    /// Statistics about the blocks written so far. See
+    /// `DeflateEncoder::stats`.
+    pub fn stats(&self) -> &CompressionStats {
+        self.encoder.stats()
+    }
+
+    /// Encodes all input written so far and flushes it to the inner writer,
+    /// ending on a byte boundary. Like zlib's `Z_SYNC_FLUSH`, this writes an
+    /// empty stored block, and costs a few bytes of compression.
+    pub fn flush_sync(&mut self) -> io::Result<()> {
+        self.encoder.write_sync_flush(&mut self.out)?;
+        self.out.get_mut().get_mut().flush()
+    }
+
+    /// Like `flush_sync`, but later output doesn't refer back to any earlier
+    /// input, so decoding can start from this point. This is zlib's
+    /// `Z_FULL_FLUSH`, and costs more compression than `flush_sync`.
+    pub fn flush_full(&mut self) -> io::Result<()> {
+        self.flush_sync()?;
+        self.encoder.reset_window();
+        Ok(())
+    }
+
+    /// Encodes all input written so far, and switches to `level` and
+    /// `strategy` for later input. The window is kept, so later input can
+    /// still refer back to earlier input. Unlike `flush_sync`, the output
+    /// isn't padded to a byte boundary, so the last few bits of it might stay
+    /// buffered.
+    pub fn set_params(&mut self, level: CompressionLevel, strategy: Strategy) -> io::Result<()>
+    where
+        M: From<EncoderOptions>,
+    {
+        while self.encoder.pending_len() > 0 {
+            self.encoder
+                .write_blocks(self.encoder.pending_len(), false, &mut self.out)?;
+        }
+
+        self.encoder.set_params(level, strategy);
+        Ok(())
+    }
+
+    /// Encodes the remaining input as the final block, and returns the inner
+    /// writer. If `EncoderOptions::verify` is set, this also waits for the
+    /// output to be checked.
+    pub fn finish(mut self) -> io::Result<W> {
+        while self.encoder.pending_len() > MAX_BYTES_PER_BLOCK {
+            self.encoder
+                .write_blocks(MAX_BYTES_PER_BLOCK, false, &mut self.out)?;
+        }
+
+        self.encoder
+            .write_blocks(self.encoder.pending_len(), true, &mut self.out)?;
+        self.out.flush_even_if_partial()?;
+        self.out.get_mut().get_mut().flush()?;
+
+        let inner = self.out.into_inner().into_inner();
+        self.encoder.finish_verifying()?;
+        Ok(inner)
+    }
+}
+
+impl<W, M> io::Write for DeflateWriter<W, M>
+where
+    W: io::Write,
+    M: MatchFinder,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.window.extend_from_slice(buf);
+
+        // Like `DeflateEncoder::encode`, keep at least one byte back, so that
+        // `finish` always has input for the final block
+        while self.encoder.pending_len() > MAX_BYTES_PER_BLOCK {
+            self.encoder
+                .write_blocks(MAX_BYTES_PER_BLOCK, false, &mut self.out)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Same as `flush_sync`.
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_sync()
+    }
+}
+
+/// A reader that compresses everything read from an inner reader, and
+/// returns it as a Deflate stream.
+///
+/// Input is read one block at a time, so at most one block of input and its
+/// encoded output are buffered, along with the window. Like
+/// `DeflateEncoder::encode`, the output doesn't depend on how the inner
+/// reader splits its input, or on how much is read at a time.
+#[derive(Debug)]
+pub struct DeflateReader<R> {
+    inner: R,
+    encoder: DeflateEncoder,
+    out: BitWriter<VerifiedOut<Vec<u8>>>,
+    /// How many bytes of `out` have been read
+    out_pos: usize,
+    is_finished: bool,
+}
+
+impl<R> DeflateReader<R>
+where
+    R: io::Read,
+{
+    pub fn new(inner: R) -> Self {
+        Self::with_options(inner, EncoderOptions::default())
+    }
+
+    pub fn with_options(inner: R, options: EncoderOptions) -> Self {
+        Self::with_encoder(inner, DeflateEncoder::with_options(options))
+    }
+
+    /// Creates a reader that encodes with a preset dictionary. See
+    /// `DeflateEncoder::with_dictionary`.
+    pub fn with_dictionary(
+        inner: R,
+        options: EncoderOptions,
+        dictionary: &[u8],
+    ) -> io::Result<Self> {
+        Ok(Self::with_encoder(
+            inner,
+            DeflateEncoder::with_dictionary(options, dictionary)?,
+        ))
+    }
+
+    fn with_encoder(inner: R, mut encoder: DeflateEncoder) -> Self {
+        let out = BitWriter::new(encoder.verified_out(vec![]));
+        Self {
+            inner,
+            encoder,
+            out,
+            out_pos: 0,
+            is_finished: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads input until there is more than a full block or the input ends,
+    /// and encodes a block. The extra byte shows whether a full block is the
+    /// last one.
+    fn encode_next_block(&mut self) -> io::Result<()> {
+        let len = MAX_BYTES_PER_BLOCK + 1 - self.encoder.pending_len();
+        (&mut self.inner)
+            .take(len as u64)
+            .read_to_end(&mut self.encoder.window)?;
+
+        if self.encoder.pending_len() > MAX_BYTES_PER_BLOCK {
+            return self
+                .encoder
+                .write_blocks(MAX_BYTES_PER_BLOCK, false, &mut self.out);
+        }
+
+        self.encoder
+            .write_blocks(self.encoder.pending_len(), true, &mut self.out)?;
+        self.out.flush_even_if_partial()?;
+        self.is_finished = true;
+
+        self.out.get_mut().stop_sending();
+        self.encoder.finish_verifying()
+    }
+}
+
+impl<R> io::Read for DeflateReader<R>
+where
+    R: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out_pos == self.out.get_ref().get_ref().len() {
+            if self.is_finished || buf.is_empty() {
+                return Ok(0);
+            }
+
+            self.out.get_mut().get_mut().clear();
+            self.out_pos = 0;
+            self.encode_next_block()?;
+        }
+
+        let out = &self.out.get_ref().get_ref()[self.out_pos..];
+        let len = buf.len().min(out.len());
+        buf[..len].copy_from_slice(&out[..len]);
+        self.out_pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{pseudorandom_bytes, sample_text, xorshift};
+
+    fn decode(compressed: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        DeflateDecoder::new()
+            .decode(&mut BitReader::new(&mut &*compressed), &mut out)
+            .unwrap();
+        out
+    }
+
+    fn encode(data: &[u8]) -> Vec<u8> {
+        encode_with_level(data, CompressionLevel::default())
+    }
+
+    fn encode_with_level(data: &[u8], level: CompressionLevel) -> Vec<u8> {
+        let mut out = vec![];
+        DeflateEncoder::with_level(level)
+            .encode(&mut &*data, &mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_decode_fixed_huffman_back_references() {
+        // zlib output for "hello hello hello world" with `Z_FIXED`
+        let compressed = [
+            0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x22, 0xcb, 0xf3, 0x8b, 0x72, 0x52,
+            0x00,
+        ];
+        assert_eq!(decode(&compressed), b"hello hello hello world");
+    }
+
+    fn back_reference_symbols() -> (Vec<Symbol>, Vec<u8>) {
+        let symbols = vec![
+            Symbol::Literal(b'a'),
+            Symbol::Literal(b'b'),
+            Symbol::BackReference {
+                length_minus_three: 255,
+                distance_minus_one: 1,
+            },
+            Symbol::BackReference {
+                length_minus_three: 9,
+                distance_minus_one: 200,
+            },
+        ];
+
+        let mut expected = b"ab".repeat(130);
+        expected.extend_from_within(59..71);
+
+        (symbols, expected)
+    }
+
+    #[test]
+    fn test_write_fixed_huffman_back_references() {
+        let (symbols, expected) = back_reference_symbols();
+
+        let mut compressed = vec![];
+        let mut out = BitWriter::new(&mut compressed);
+        write_block_header(true, DeflateEncoding::FixedHuffman, &mut out).unwrap();
+        write_huffman_block(
+            &symbols,
+            &HuffmanCode::fixed_literal(),
+            &HuffmanCode::fixed_distance(),
+            &mut out,
+        )
+        .unwrap();
+        out.flush_even_if_partial().unwrap();
+
+        assert_eq!(decode(&compressed), expected);
+    }
+
+    #[test]
+    fn test_write_dynamic_huffman_back_references() {
+        let (symbols, expected) = back_reference_symbols();
+
+        let (literal_frequencies, distance_frequencies) = symbol_frequencies(&symbols);
+        let literal_code = HuffmanCode::from_frequencies(&literal_frequencies, MAX_CODE_LENGTH);
+        let distance_code = HuffmanCode::from_frequencies(&distance_frequencies, MAX_CODE_LENGTH);
+
+        let mut compressed = vec![];
+        let mut out = BitWriter::new(&mut compressed);
+        write_block_header(true, DeflateEncoding::DynamicHuffman, &mut out).unwrap();
+        DynamicHeader::new(&literal_code, &distance_code)
+            .write(&mut out)
+            .unwrap();
+        write_huffman_block(&symbols, &literal_code, &distance_code, &mut out).unwrap();
+        out.flush_even_if_partial().unwrap();
+
+        assert_eq!(decode(&compressed), expected);
+    }
+
+    #[test]
+    fn test_decode_code_length_run_across_codes() {
+        // Without back-references, the literal/length code lengths end in
+        // zeros. So do the distance code lengths up to the last two, which
+        // only fill the code. zlib can encode the zeros as one run.
+        let data = b"abracadabra";
+        let symbols: Vec<_> = data.iter().map(|&byte| Symbol::Literal(byte)).collect();
+        let (literal_code, _) = dynamic_codes(&symbols);
+        let distance_code_lengths = [&[0; DISTANCE_CODE_COUNT - 2][..], &[1, 1]].concat();
+        let distance_code = HuffmanCode::from_code_lengths(&distance_code_lengths);
+
+        let code_lengths = [literal_code.code_lengths(), &distance_code_lengths].concat();
+        let header = DynamicHeader::with_code_length_symbols(
+            LENGTH_CODE_COUNT as u16,
+            DISTANCE_CODE_COUNT as u16,
+            huffman::encode_code_lengths(&code_lengths),
+        );
+        let runs = &header.code_length_symbols;
+        let crossing_run = &runs[runs.len() - 3];
+        let run_len = (LENGTH_CODE_COUNT - 257) + (DISTANCE_CODE_COUNT - 2);
+        assert_eq!(crossing_run.symbol, 18);
+        assert_eq!(usize::from(crossing_run.extra_bits) + 11, run_len);
+
+        let mut compressed = vec![];
+        let mut out = BitWriter::new(&mut compressed);
+        write_block_header(true, DeflateEncoding::DynamicHuffman, &mut out).unwrap();
+        header.write(&mut out).unwrap();
+        write_huffman_block(&symbols, &literal_code, &distance_code, &mut out).unwrap();
+        out.flush_even_if_partial().unwrap();
+
+        assert_eq!(decode(&compressed), data);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for data in [
+            vec![],
+            b"a".to_vec(),
+            sample_text(),
+            pseudorandom_bytes(20_000),
+        ] {
+            assert_eq!(decode(&encode(&data)), data);
+        }
+    }
+
+    #[test]
+    fn test_compression_levels() {
+        let text = sample_text();
+        let mut sizes = vec![];
+
+        for level in 0..=9 {
+            let level = CompressionLevel::try_from(level).unwrap();
+            let compressed = encode_with_level(&text, level);
+            assert_eq!(decode(&compressed), text);
+            sizes.push(compressed.len());
+        }
+
+        // Level 0 is a single stored block
+        assert_eq!(sizes[0], text.len() + 5);
+        assert!(sizes[9] <= sizes[1]);
+        assert!(sizes[1] < sizes[0] / 10);
+        assert!(CompressionLevel::try_from(10).is_err());
+    }
+
+    #[test]
+    fn test_lazy_matching_options() {
+        let text = sample_text();
+
+        let greedy = EncoderOptions {
+            max_lazy_length: 0,
+            ..EncoderOptions::default()
+        };
+        let lazy = EncoderOptions {
+            max_lazy_length: 258,
+            ..EncoderOptions::default()
+        };
+
+        for options in [greedy, lazy] {
+            let mut compressed = vec![];
+            DeflateEncoder::with_options(options)
+                .encode(&mut &*text, &mut compressed)
+                .unwrap();
+            assert_eq!(decode(&compressed), text);
+        }
+    }
+
+    #[test]
+    fn test_optimal_parsing() {
+        let text = sample_text();
+        let optimal = EncoderOptions {
+            optimal_parse_iterations: 5,
+            ..CompressionLevel::BEST.into()
+        };
+
+        let mut compressed = vec![];
+        DeflateEncoder::with_options(optimal)
+            .encode(&mut &*text, &mut compressed)
+            .unwrap();
+
+        assert_eq!(decode(&compressed), text);
+        assert!(compressed.len() <= encode_with_level(&text, CompressionLevel::BEST).len());
+    }
+
+    #[test]
+    fn test_read_after_stream() {
+        let mut data = encode(b"hello, hello, hello");
+        data.extend_from_slice(b"trailing data");
+
+        let mut in_ = &data[..];
+        let mut bit_in = BitReader::new(&mut in_);
+        let mut out = vec![];
+        DeflateDecoder::new().decode(&mut bit_in, &mut out).unwrap();
+        assert_eq!(out, b"hello, hello, hello");
+
+        let mut rest = vec![];
+        bit_in.into_inner().read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"trailing data");
+    }
+
+    #[test]
+    fn test_decode_back_reference_into_stored_block() {
+        let mut compressed = vec![];
+        let mut out = BitWriter::new(&mut compressed);
+        write_block_header(false, DeflateEncoding::NoCompression, &mut out).unwrap();
+        write_stored_block(b"abcdef", &mut out).unwrap();
+        write_block_header(true, DeflateEncoding::FixedHuffman, &mut out).unwrap();
+        write_huffman_block(
+            &[Symbol::BackReference {
+                length_minus_three: 3,
+                distance_minus_one: 5,
+            }],
+            &HuffmanCode::fixed_literal(),
+            &HuffmanCode::fixed_distance(),
+            &mut out,
+        )
+        .unwrap();
+        out.flush_even_if_partial().unwrap();
+
+        assert_eq!(decode(&compressed), b"abcdefabcdef");
+    }
+
+    #[test]
+    fn test_chooses_smallest_block_type() {
+        let block_type = |compressed: &[u8]| (compressed[0] >> 1) & 0b11;
+
+        // Too short for a dynamic header to pay off
+        let compressed = encode(b"hello");
+        assert_eq!(block_type(&compressed), 0b01);
+        assert_eq!(decode(&compressed), b"hello");
+
+        let compressed = encode(&sample_text());
+        assert_eq!(block_type(&compressed), 0b10);
+
+        // Incompressible data is stored rather than expanded
+        let random = pseudorandom_bytes(150_000);
+        let compressed = encode(&random);
+        assert_eq!(block_type(&compressed), 0b00);
+        assert_eq!(compressed.len(), random.len() + 3 * 5);
+        assert_eq!(decode(&compressed), random);
+    }
+
+    #[test]
+    fn test_round_trip_mixed_content() {
+        let data = [sample_text(), pseudorandom_bytes(70_000), sample_text()].concat();
+        assert_eq!(decode(&encode(&data)), data);
+    }
+
+    #[test]
+    fn test_block_splitting() {
+        // Text followed by binary data with a very different byte distribution
+        let binary = pseudorandom_bytes(10_000)
+            .into_iter()
+            .map(|byte| 0xc0 | (byte & 0x3f))
+            .collect::<Vec<_>>();
+        let data = [sample_text(), binary, sample_text()].concat();
+
+        let split = EncoderOptions {
+            split_blocks: true,
+            ..EncoderOptions::default()
+        };
+        let mut compressed = vec![];
+        DeflateEncoder::with_options(split)
+            .encode(&mut &*data, &mut compressed)
+            .unwrap();
+
+        assert_eq!(decode(&compressed), data);
+        assert!(compressed.len() < encode(&data).len());
+    }
+
+    #[test]
+    fn test_strategies() {
+        let text = sample_text();
+        let runs = (0..20_000u32)
+            .map(|i| (i / 100 % 7).try_into().unwrap())
+            .collect::<Vec<u8>>();
+
+        let mut sizes = vec![];
+        for strategy in [
+            Strategy::Default,
+            Strategy::Filtered,
+            Strategy::HuffmanOnly,
+            Strategy::Rle,
+        ] {
+            let options = EncoderOptions {
+                strategy,
+                ..EncoderOptions::default()
+            };
+
+            for data in [&text, &runs] {
+                let mut compressed = vec![];
+                DeflateEncoder::with_options(options)
+                    .encode(&mut &**data, &mut compressed)
+                    .unwrap();
+                assert_eq!(&decode(&compressed), data);
+                sizes.push(compressed.len());
+            }
+        }
+
+        // Huffman coding alone can't take advantage of repeated text, but
+        // run-length encoding handles runs well
+        let [_, _, _, _, huffman_only_text, _, rle_text, rle_runs] = sizes[..] else {
+            unreachable!();
+        };
+        assert!(huffman_only_text > 10 * sizes[0]);
+        assert!(rle_text <= huffman_only_text);
+        assert!(rle_runs < runs.len() / 20);
+    }
+
+    #[test]
+    fn test_stored_blocks_round_trip() {
+        let data = pseudorandom_bytes(150_000);
+        let compressed = encode_with_level(&data, CompressionLevel::NONE);
+        assert_eq!(compressed.len(), data.len() + 3 * 5);
+        assert_eq!(decode(&compressed), data);
+    }
+
+    fn write_in_chunks<W, M>(writer: &mut DeflateWriter<W, M>, data: &[u8], chunk_len: usize)
+    where
+        W: io::Write,
+        M: MatchFinder,
+    {
+        for chunk in data.chunks(chunk_len) {
+            io::Write::write_all(writer, chunk).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_writer_matches_encoder() {
+        let data = sample_text().repeat(8);
+        let level = CompressionLevel::FASTEST;
+
+        for chunk_len in [1000, 65535, data.len()] {
+            let mut writer = DeflateWriter::with_options(vec![], level.into());
+            write_in_chunks(&mut writer, &data, chunk_len);
+            assert_eq!(writer.finish().unwrap(), encode_with_level(&data, level));
+        }
+    }
+
+    #[test]
+    fn test_writer_empty() {
+        let compressed = DeflateWriter::new(vec![]).finish().unwrap();
+        assert_eq!(decode(&compressed), b"");
+    }
+
+    #[test]
+    fn test_writer_flush_sync() {
+        let data = sample_text();
+        let (first, second) = data.split_at(3000);
+
+        let mut writer = DeflateWriter::new(vec![]);
+        write_in_chunks(&mut writer, first, 100);
+        writer.flush_sync().unwrap();
+        assert!(writer.get_ref().ends_with(&[0, 0, 0xff, 0xff]));
+
+        // Everything written so far can be decoded from the flushed output
+        let flushed_len = writer.get_ref().len();
+        write_in_chunks(&mut writer, second, 100);
+        let compressed = writer.finish().unwrap();
+        assert_eq!(decode(&compressed), data);
+
+        let mut out = vec![];
+        let mut decoder = DeflateDecoder::new();
+        let result = decoder.decode(
+            &mut BitReader::new(&mut &compressed[..flushed_len]),
+            &mut out,
+        );
+        assert!(result.is_err());
+        assert_eq!(out, first);
+    }
+
+    #[test]
+    fn test_writer_flush_full() {
+        let data = sample_text();
+        let (first, second) = data.split_at(3000);
+
+        let mut writer = DeflateWriter::new(vec![]);
+        io::Write::write_all(&mut writer, first).unwrap();
+        writer.flush_full().unwrap();
+        let flushed_len = writer.get_ref().len();
+        io::Write::write_all(&mut writer, second).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        assert_eq!(decode(&compressed), data);
+        // Decoding can start right after a full flush
+        assert_eq!(decode(&compressed[flushed_len..]), second);
+    }
+
+    #[test]
+    fn test_writer_set_params() {
+        // Only compressible by referring back to the previous copy
+        let data = pseudorandom_bytes(10_000);
+        let mut writer = DeflateWriter::with_options(vec![], CompressionLevel::BEST.into());
+        write_in_chunks(&mut writer, &data, 1000);
+
+        let params = [
+            (CompressionLevel::FASTEST, Strategy::Default),
+            (CompressionLevel::NONE, Strategy::Default),
+            (CompressionLevel::DEFAULT, Strategy::HuffmanOnly),
+            (CompressionLevel::DEFAULT, Strategy::Rle),
+            (CompressionLevel::DEFAULT, Strategy::Default),
+        ];
+        for (level, strategy) in params {
+            writer.set_params(level, strategy).unwrap();
+            write_in_chunks(&mut writer, &data, 1000);
+        }
+        // Setting the parameters twice in a row writes nothing
+        writer
+            .set_params(CompressionLevel::BEST, Strategy::Default)
+            .unwrap();
+        writer
+            .set_params(CompressionLevel::BEST, Strategy::Filtered)
+            .unwrap();
+
+        // The last copy refers back to the window from before the change
+        let len_before_last_copy = writer.get_ref().len();
+        write_in_chunks(&mut writer, &data, 1000);
+        let compressed = writer.finish().unwrap();
+        assert!(compressed.len() - len_before_last_copy < 200);
+        assert_eq!(decode(&compressed), data.repeat(params.len() + 2));
+
+        // With another match finder, and keeping the options that aren't part
+        // of a level
+        let options = EncoderOptions {
+            split_blocks: true,
+            optimal_parse_iterations: 2,
+            ..CompressionLevel::FASTEST.into()
+        };
+        let encoder = DeflateEncoder::<BinaryTree>::with_match_finder(options, options.into());
+        let mut writer = DeflateWriter::with_encoder(vec![], encoder);
+        write_in_chunks(&mut writer, &data, 1000);
+        writer
+            .set_params(CompressionLevel::BEST, Strategy::Default)
+            .unwrap();
+        assert!(writer.encoder.options.split_blocks);
+        assert_eq!(writer.encoder.options.optimal_parse_iterations, 2);
+
+        write_in_chunks(&mut writer, &data, 1000);
+        assert_eq!(decode(&writer.finish().unwrap()), data.repeat(2));
+    }
+
+    #[test]
+    fn test_rsyncable() {
+        let options = EncoderOptions {
+            rsyncable: true,
+            ..EncoderOptions::default()
+        };
+        let encode_rsyncable = |data: &[u8]| {
+            let mut compressed = vec![];
+            DeflateEncoder::with_options(options)
+                .encode(&mut &*data, &mut compressed)
+                .unwrap();
+            compressed
+        };
+
+        let data: Vec<u8> = pseudorandom_bytes(80_000)
+            .iter()
+            .map(|byte| b'a' + byte % 16)
+            .collect();
+        let compressed = encode_rsyncable(&data);
+        assert_eq!(decode(&compressed), data);
+
+        let mut writer = DeflateWriter::with_options(vec![], options);
+        write_in_chunks(&mut writer, &data, 1000);
+        assert_eq!(writer.finish().unwrap(), compressed);
+
+        // After an inserted byte, the output is the same again from the next
+        // flush
+        let mut edited = data.clone();
+        edited.insert(10_000, b'!');
+        let edited_compressed = encode_rsyncable(&edited);
+        assert_eq!(decode(&edited_compressed), edited);
+
+        let common_suffix_len = iter::zip(compressed.iter().rev(), edited_compressed.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        assert!(common_suffix_len > compressed.len() * 3 / 4);
+    }
+
+    /// Endless pseudorandom chunk lengths, mostly short but some longer than
+    /// a block.
+    fn random_chunk_lens(seed: u32) -> impl Iterator<Item = usize> {
+        let mut state = seed;
+        iter::repeat_with(move || {
+            xorshift(&mut state);
+            let max_len = if state & 0xf == 0 { 100_000 } else { 300 };
+            (state >> 4) as usize % max_len + 1
+        })
+    }
+
+    /// Reads `data` in chunks of the given lengths.
+    struct ChunkedReader<'a, I> {
+        data: &'a [u8],
+        chunk_lens: I,
+    }
+
+    impl<I> io::Read for ChunkedReader<'_, I>
+    where
+        I: Iterator<Item = usize>,
+    {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.chunk_lens.next().unwrap().min(buf.len());
+            (&mut self.data).read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_output_independent_of_chunking() {
+        let mut data = sample_text();
+        data.extend(pseudorandom_bytes(30_000));
+        data.extend(sample_text().repeat(5));
+        let dictionary = &data[1000..2000];
+
+        let configs = [
+            (CompressionLevel::NONE.into(), None),
+            (CompressionLevel::FASTEST.into(), Some(dictionary)),
+            (
+                EncoderOptions {
+                    split_blocks: true,
+                    ..EncoderOptions::default()
+                },
+                None,
+            ),
+            (
+                EncoderOptions {
+                    rsyncable: true,
+                    ..CompressionLevel::FASTEST.into()
+                },
+                None,
+            ),
+        ];
+
+        for (options, dictionary) in configs {
+            let new_encoder = || match dictionary {
+                Some(dictionary) => DeflateEncoder::with_dictionary(options, dictionary).unwrap(),
+                None => DeflateEncoder::with_options(options),
+            };
+
+            let mut expected = vec![];
+            new_encoder().encode(&mut &*data, &mut expected).unwrap();
+
+            for seed in [1, 2] {
+                let mut compressed = vec![];
+                let mut in_ = ChunkedReader {
+                    data: &data,
+                    chunk_lens: random_chunk_lens(seed),
+                };
+                new_encoder().encode(&mut in_, &mut compressed).unwrap();
+                assert_eq!(compressed, expected);
+
+                let mut writer = DeflateWriter::with_encoder(vec![], new_encoder());
+                let mut rest = &data[..];
+                for len in random_chunk_lens(seed) {
+                    let (chunk, tail) = rest.split_at(len.min(rest.len()));
+                    io::Write::write_all(&mut writer, chunk).unwrap();
+                    rest = tail;
+                    if rest.is_empty() {
+                        break;
+                    }
+                }
+                assert_eq!(writer.finish().unwrap(), expected);
+
+                let in_ = ChunkedReader {
+                    data: &data,
+                    chunk_lens: random_chunk_lens(seed),
+                };
+                let mut reader = DeflateReader::with_encoder(in_, new_encoder());
+                let mut compressed = vec![];
+                for len in random_chunk_lens(seed + 100) {
+                    let mut buf = vec![0; len];
+                    let bytes = io::Read::read(&mut reader, &mut buf).unwrap();
+                    if bytes == 0 {
+                        break;
+                    }
+                    compressed.extend_from_slice(&buf[..bytes]);
+                }
+                assert_eq!(compressed, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_preset_dictionary() {
+        let dictionary = br#"{"id": , "name": "", "tags": ["alpha", "beta"], "active": true}"#;
+        let data = br#"{"id": 17, "name": "widget", "tags": ["beta"], "active": true}"#;
+
+        let mut compressed = vec![];
+        DeflateEncoder::<HashChain>::with_dictionary(EncoderOptions::default(), dictionary)
+            .unwrap()
+            .encode(&mut &data[..], &mut compressed)
+            .unwrap();
+        assert!(compressed.len() < encode(data).len());
+
+        let mut out = vec![];
+        DeflateDecoder::with_dictionary(dictionary)
+            .unwrap()
+            .decode(&mut BitReader::new(&mut &*compressed), &mut out)
+            .unwrap();
+        assert_eq!(out, data);
+
+        // Without the dictionary, back-references reach before the start
+        let mut out = vec![];
+        assert!(DeflateDecoder::new()
+            .decode(&mut BitReader::new(&mut &*compressed), &mut out)
+            .is_err());
+
+        // Other match finders can use a dictionary too
+        let mut compressed = vec![];
+        DeflateEncoder::<BinaryTree>::with_dictionary(EncoderOptions::default(), dictionary)
+            .unwrap()
+            .encode(&mut &data[..], &mut compressed)
+            .unwrap();
+        assert!(compressed.len() < encode(data).len());
+
+        let mut out = vec![];
+        DeflateDecoder::with_dictionary(dictionary)
+            .unwrap()
+            .decode(&mut BitReader::new(&mut &*compressed), &mut out)
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_preset_dictionary_too_long() {
+        let dictionary = vec![0; MAX_DISTANCE_BYTES + 1];
+        assert!(DeflateEncoder::<HashChain>::with_dictionary(
+            EncoderOptions::default(),
+            &dictionary
+        )
+        .is_err());
+        assert!(DeflateDecoder::with_dictionary(&dictionary).is_err());
+    }
+
+    #[test]
+    fn test_compress_bound() {
+        // Random data with text mixed in, so that some blocks are split off and
+        // stored, starting partway through a byte
+        let mut mixed = vec![];
+        for chunk in pseudorandom_bytes(20_000).chunks(2000) {
+            mixed.extend_from_slice(chunk);
+            mixed.extend_from_slice(&sample_text()[..500]);
+        }
+
+        let inputs = [
+            (vec![], false),
+            (vec![0], false),
+            (pseudorandom_bytes(100), false),
+            // Input that fills its last block exactly
+            (pseudorandom_bytes(MAX_BYTES_PER_BLOCK), false),
+            (pseudorandom_bytes(MAX_BYTES_PER_BLOCK + 1), false),
+            (pseudorandom_bytes(2 * MAX_BYTES_PER_BLOCK), false),
+            (mixed.clone(), false),
+            (mixed, true),
+        ];
+
+        for level in 0..=9 {
+            for (input, split_blocks) in &inputs {
+                let options = EncoderOptions {
+                    split_blocks: *split_blocks,
+                    ..CompressionLevel::try_from(level).unwrap().into()
+                };
+
+                let mut encoder = DeflateEncoder::with_options(options);
+                let bound = encoder.compress_bound(input.len());
+
+                let mut compressed = vec![];
+                encoder.encode(&mut &input[..], &mut compressed).unwrap();
+                assert!(
+                    compressed.len() <= bound,
+                    "level {level}: {} > {bound}",
+                    compressed.len()
+                );
+            }
+        }
+
+        let encoder = DeflateEncoder::new();
+        assert_eq!(encoder.compress_bound(0), 5);
+        assert_eq!(
+            encoder.compress_bound(MAX_BYTES_PER_BLOCK),
+            MAX_BYTES_PER_BLOCK + 5
+        );
+        assert_eq!(
+            encoder.compress_bound(MAX_BYTES_PER_BLOCK + 1),
+            MAX_BYTES_PER_BLOCK + 11
+        );
+
+        // Incompressible input with many rsync points, each of which adds a
+        // flush and splits a block
+        let input = pseudorandom_bytes(4 * MAX_BYTES_PER_BLOCK);
+        for level in [CompressionLevel::NONE, CompressionLevel::BEST] {
+            let options = EncoderOptions {
+                rsyncable: true,
+                ..level.into()
+            };
+            let mut encoder = DeflateEncoder::with_options(options);
+            let bound = encoder.compress_bound(input.len());
+
+            let mut compressed = vec![];
+            encoder.encode(&mut &input[..], &mut compressed).unwrap();
+            assert!(compressed.len() <= bound, "{} > {bound}", compressed.len());
+            assert!(compressed.len() > DeflateEncoder::new().compress_bound(input.len()));
+        }
+
+        // Each flush of a `DeflateWriter` adds at most 10 bytes
+        let input = pseudorandom_bytes(MAX_BYTES_PER_BLOCK + 1);
+        let mut writer = DeflateWriter::with_options(vec![], CompressionLevel::BEST.into());
+        let mut flush_count = 0;
+        for chunk in input.chunks(10_000) {
+            io::Write::write_all(&mut writer, chunk).unwrap();
+            writer.flush_sync().unwrap();
+            flush_count += 1;
+        }
+        let bound = encoder.compress_bound(input.len()) + 10 * flush_count;
+        assert!(writer.finish().unwrap().len() <= bound);
+    }
+
+    fn encode_symbols(symbols: Vec<Symbol>, options: EncoderOptions) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+        DeflateEncoder::with_options(options).encode_symbols(symbols, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_encode_symbols() {
+        let (symbols, expected) = back_reference_symbols();
+        let compressed = encode_symbols(symbols.clone(), EncoderOptions::default()).unwrap();
+        assert_eq!(decode(&compressed), expected);
+
+        // Each end of block starts a new block
+        let with_blocks = symbols
+            .iter()
+            .flat_map(|&symbol| [symbol, Symbol::EndOfBlock])
+            .collect();
+        let compressed_blocks = encode_symbols(with_blocks, EncoderOptions::default()).unwrap();
+        assert!(compressed_blocks.len() > compressed.len());
+        assert_eq!(decode(&compressed_blocks), expected);
+
+        let compressed = encode_symbols(vec![], EncoderOptions::default()).unwrap();
+        assert_eq!(decode(&compressed), b"");
+    }
+
+    #[test]
+    fn test_encode_symbols_large_blocks() {
+        // Decodes to more than fits in one stored block
+        let symbols: Vec<_> = iter::once(Symbol::Literal(b'x'))
+            .chain(iter::repeat(Symbol::BackReference {
+                length_minus_three: 255,
+                distance_minus_one: 0,
+            }))
+            .take(300)
+            .collect();
+
+        let compressed = encode_symbols(symbols, EncoderOptions::default()).unwrap();
+        assert_eq!(decode(&compressed), vec![b'x'; 1 + 299 * 258]);
+    }
+
+    #[test]
+    fn test_encode_symbols_checks_distances() {
+        let symbols = vec![
+            Symbol::Literal(b'a'),
+            Symbol::Literal(b'b'),
+            Symbol::BackReference {
+                length_minus_three: 0,
+                distance_minus_one: 2,
+            },
+        ];
+        assert!(encode_symbols(symbols, EncoderOptions::default()).is_err());
+
+        let mut symbols = vec![Symbol::Literal(0); 1000];
+        symbols.push(Symbol::BackReference {
+            length_minus_three: 0,
+            distance_minus_one: 600,
+        });
+        assert!(encode_symbols(symbols.clone(), EncoderOptions::default()).is_ok());
+
+        let options = EncoderOptions {
+            window_bits: WindowBits::MIN,
+            ..EncoderOptions::default()
+        };
+        assert!(encode_symbols(symbols, options).is_err());
+    }
+
+    #[test]
+    fn test_encode_symbols_writes_symbols_before_error() {
+        let mut symbols = b"abcdef".map(Symbol::Literal).to_vec();
+        symbols.push(Symbol::BackReference {
+            length_minus_three: 0,
+            distance_minus_one: 100,
+        });
+
+        let mut out = vec![];
+        assert!(DeflateEncoder::new()
+            .encode_symbols(symbols, &mut out)
+            .is_err());
+
+        // The stream has no final block, but everything before the error
+        // decodes
+        let mut decoder = DeflateDecoder::new();
+        let mut decoded = vec![];
+        assert!(decoder
+            .decode(&mut BitReader::new(&mut &out[..]), &mut decoded)
+            .is_err());
+        assert_eq!(decoded, b"abcdef");
+    }
+
+    #[test]
+    fn test_encode_symbols_stored() {
+        let (symbols, expected) = back_reference_symbols();
+        let compressed = encode_symbols(symbols, CompressionLevel::NONE.into()).unwrap();
+        assert_eq!(decode(&compressed), expected);
+        assert!(
+            decode_items(&compressed).contains(&DecodedItem::BlockStart {
+                is_final: true,
+                encoding: DeflateEncoding::NoCompression,
+            })
+        );
+    }
+
+    #[test]
+    fn test_encode_symbols_after_finish() {
+        let (symbols, expected) = back_reference_symbols();
+        let mut encoder = DeflateEncoder::new();
+        let mut compressed = vec![];
+        encoder
+            .encode_symbols(symbols.clone(), &mut compressed)
+            .unwrap();
+
+        // Nothing can be written after the final block
+        assert!(encoder.encode_symbols(symbols, &mut compressed).is_err());
+        assert_eq!(decode(&compressed), expected);
+    }
+
+    fn decode_items(compressed: &[u8]) -> Vec<DecodedItem> {
+        DeflateDecoder::new()
+            .symbols(&mut BitReader::new(&mut &*compressed))
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_decode_symbols() {
+        let (symbols, _) = back_reference_symbols();
+        let compressed = encode_symbols(symbols.clone(), EncoderOptions::default()).unwrap();
+
+        let mut expected = vec![DecodedItem::BlockStart {
+            is_final: true,
+            encoding: DeflateEncoding::FixedHuffman,
+        }];
+        expected.extend(symbols.into_iter().map(DecodedItem::Symbol));
+        expected.push(DecodedItem::Symbol(Symbol::EndOfBlock));
+        assert_eq!(decode_items(&compressed), expected);
+    }
+
+    #[test]
+    fn test_compression_stats() {
+        let mut data = sample_text();
+        data.extend(pseudorandom_bytes(20_000));
+        data.extend(sample_text().repeat(10));
+
+        for level in [CompressionLevel::NONE, CompressionLevel::DEFAULT] {
+            let options = EncoderOptions {
+                split_blocks: true,
+                ..level.into()
+            };
+            let mut encoder = DeflateEncoder::with_options(options);
+            let mut compressed = vec![];
+            encoder.encode(&mut &*data, &mut compressed).unwrap();
+
+            let stats = encoder.stats();
+            assert_eq!(stats.input_bytes(), data.len());
+            assert_eq!(stats.output_bits().div_ceil(8), compressed.len() as u64);
+
+            // Compare each block with what the decoder finds in it
+            let mut decoded_blocks = vec![];
+            for item in decode_items(&compressed) {
+                match item {
+                    DecodedItem::BlockStart { is_final, encoding } => {
+                        decoded_blocks.push((encoding, is_final, 0, 0));
+                    }
+                    DecodedItem::Symbol(Symbol::Literal(_)) => {
+                        decoded_blocks.last_mut().unwrap().2 += 1
+                    }
+                    DecodedItem::Symbol(Symbol::BackReference { .. }) => {
+                        decoded_blocks.last_mut().unwrap().3 += 1;
+                    }
+                    DecodedItem::Symbol(Symbol::EndOfBlock) => {}
+                }
+            }
+            let blocks: Vec<_> = stats
+                .blocks
+                .iter()
+                .map(|block| {
+                    (
+                        block.encoding,
+                        block.is_final,
+                        block.literal_count,
+                        block.match_count,
+                    )
+                })
+                .collect();
+            assert_eq!(blocks, decoded_blocks);
+
+            for block in &stats.blocks {
+                let distance_count: u32 = block.match_distance_histogram.iter().sum();
+                assert_eq!(distance_count as usize, block.match_count);
+
+                let histograms = block.code_length_histograms.as_ref();
+                assert_eq!(
+                    histograms.is_some(),
+                    block.encoding == DeflateEncoding::DynamicHuffman
+                );
+                if let Some(histograms) = histograms {
+                    assert_eq!(histograms.literal_length.iter().sum::<u32>(), 286);
+                    assert_eq!(histograms.distance.iter().sum::<u32>(), 30);
+                }
+            }
+        }
+
+        // Flushes show up as empty stored blocks
+        let mut writer = DeflateWriter::new(vec![]);
+        io::Write::write_all(&mut writer, b"abc").unwrap();
+        writer.flush_sync().unwrap();
+        let flush_block = writer.stats().blocks.last().unwrap().clone();
+        assert_eq!(flush_block.encoding, DeflateEncoding::NoCompression);
+        assert_eq!(flush_block.input_bytes, 0);
+        assert_eq!(
+            writer.stats().output_bits().div_ceil(8),
+            writer.get_ref().len() as u64
+        );
+    }
+
+    #[test]
+    fn test_decode_symbols_stored_blocks() {
+        let compressed = encode_with_level(b"abc", CompressionLevel::NONE);
+        assert_eq!(
+            decode_items(&compressed),
+            [
+                DecodedItem::BlockStart {
+                    is_final: true,
+                    encoding: DeflateEncoding::NoCompression,
+                },
+                DecodedItem::Symbol(Symbol::Literal(b'a')),
+                DecodedItem::Symbol(Symbol::Literal(b'b')),
+                DecodedItem::Symbol(Symbol::Literal(b'c')),
+                DecodedItem::Symbol(Symbol::EndOfBlock),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_symbols_re_encode() {
+        let data = sample_text();
+        let compressed = encode(&data);
+
+        let symbols: Vec<_> = decode_items(&compressed)
+            .into_iter()
+            .filter_map(|item| match item {
+                DecodedItem::Symbol(symbol) => Some(symbol),
+                DecodedItem::BlockStart { .. } => None,
+            })
+            .collect();
+        let re_encoded = encode_symbols(symbols, EncoderOptions::default()).unwrap();
+        assert_eq!(re_encoded, compressed);
+    }
+
+    #[test]
+    fn test_decode_symbols_stops_on_error() {
+        // A block with the invalid encoding 0b11
+        let compressed = [0b111];
+        let mut reader = &compressed[..];
+        let mut decoder = DeflateDecoder::new();
+        let mut in_ = BitReader::new(&mut reader);
+        let mut symbols = decoder.symbols(&mut in_);
+
+        assert!(symbols.next().unwrap().is_err());
+        assert!(symbols.next().is_none());
+    }
+
+    #[test]
+    fn test_binary_tree_match_finder() {
+        let data = sample_text();
+
+        for level in [
+            CompressionLevel::FASTEST,
+            CompressionLevel::DEFAULT,
+            CompressionLevel::BEST,
+        ] {
+            let options = EncoderOptions::from(level);
+            let mut compressed = vec![];
+            DeflateEncoder::<BinaryTree>::with_match_finder(options, options.into())
+                .encode(&mut &data[..], &mut compressed)
+                .unwrap();
+
+            assert_eq!(decode(&compressed), data);
+            assert!(compressed.len() <= encode_with_level(&data, level).len() + 8);
+        }
+    }
+
+    #[test]
+    fn test_encode_bounded() {
+        let text = sample_text();
+        let random = pseudorandom_bytes(3000);
+        let long = sample_text().repeat(10);
+
+        for (input, level) in [
+            (&text, CompressionLevel::DEFAULT),
+            (&text, CompressionLevel::NONE),
+            (&random, CompressionLevel::DEFAULT),
+            (&long, CompressionLevel::FASTEST),
+        ] {
+            for capacity in [2, 3, 10, 100, 1024, 4000, 100_000] {
+                let mut output = vec![0; capacity];
+                let (consumed, written) = DeflateEncoder::with_level(level)
+                    .encode_bounded(input, &mut output)
+                    .unwrap();
+
+                assert!(written <= capacity);
+                assert_eq!(decode(&output[..written]), input[..consumed]);
+                if consumed < input.len() {
+                    // Nearly all of the output is used
+                    assert!(written + 8 >= capacity, "{written} of {capacity}");
+                }
+            }
+        }
+
+        let mut output = [0; 1];
+        assert!(DeflateEncoder::new()
+            .encode_bounded(b"", &mut output)
+            .is_err());
+        let mut output = [0; 2];
+        assert_eq!(
+            DeflateEncoder::new()
+                .encode_bounded(b"", &mut output)
+                .unwrap(),
+            (0, 2)
+        );
+    }
+
+    #[test]
+    fn test_encode_bounded_after_finish() {
+        let data = sample_text();
+        let mut output = vec![0; 100_000];
+
+        let mut encoder = DeflateEncoder::new();
+        let (consumed, written) = encoder.encode_bounded(&data, &mut output).unwrap();
+        assert_eq!(decode(&output[..written]), data[..consumed]);
+        assert!(encoder.encode_bounded(&data, &mut output).is_err());
+        assert!(encoder.encode(&mut &data[..], &mut vec![]).is_err());
+
+        let mut encoder = DeflateEncoder::new();
+        encoder.encode(&mut &data[..], &mut vec![]).unwrap();
+        assert!(encoder.encode_bounded(&data, &mut output).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let data = sample_text().repeat(30);
+        let dictionary = &data[..1000];
+
+        for level in [0, 1, 6, 9] {
+            let options = EncoderOptions {
+                verify: true,
+                ..CompressionLevel::try_from(level).unwrap().into()
+            };
+
+            let mut compressed = vec![];
+            DeflateEncoder::with_options(options)
+                .encode(&mut &*data, &mut compressed)
+                .unwrap();
+            assert_eq!(compressed, encode_with_level(&data, options.level));
+
+            let mut output = vec![0; 1000];
+            let (consumed, written) = DeflateEncoder::with_options(options)
+                .encode_bounded(&data, &mut output)
+                .unwrap();
+            assert_eq!(decode(&output[..written]), data[..consumed]);
+
+            let mut writer = DeflateWriter::with_dictionary(vec![], options, dictionary).unwrap();
+            write_in_chunks(&mut writer, &data[..40_000], 1000);
+            writer.flush_full().unwrap();
+            write_in_chunks(&mut writer, &data[40_000..], 1000);
+            writer.finish().unwrap();
+        }
+
+        let (symbols, expected) = back_reference_symbols();
+        let options = EncoderOptions {
+            verify: true,
+            ..EncoderOptions::default()
+        };
+        let compressed = encode_symbols(symbols, options).unwrap();
+        assert_eq!(decode(&compressed), expected);
+    }
+
+    #[test]
+    fn test_verify_mismatch() {
+        let data = sample_text().repeat(30);
+        let options = EncoderOptions {
+            verify: true,
+            ..EncoderOptions::default()
+        };
+
+        let mut encoder = DeflateEncoder::with_options(options);
+        encoder.corrupt_verification();
+        assert!(encoder.encode(&mut &*data, &mut vec![]).is_err());
+
+        let mut encoder = DeflateEncoder::with_options(options);
+        encoder.corrupt_verification();
+        assert!(encoder.encode_bounded(&data, &mut [0; 1000]).is_err());
+
+        let (symbols, _) = back_reference_symbols();
+        let mut encoder = DeflateEncoder::with_options(options);
+        encoder.corrupt_verification();
+        assert!(encoder.encode_symbols(symbols, &mut vec![]).is_err());
+
+        let mut writer = DeflateWriter::with_options(vec![], options);
+        write_in_chunks(&mut writer, &data, 1000);
+        writer.encoder.corrupt_verification();
+        assert!(writer.finish().is_err());
+    }
+
+    /// Reads everything from `reader`, `chunk_len` bytes at a time.
+    fn read_in_chunks<R>(reader: &mut R, chunk_len: usize) -> Vec<u8>
+    where
+        R: io::Read,
+    {
+        let mut out = vec![];
+        let mut chunk = vec![0; chunk_len];
+        loop {
+            let bytes = reader.read(&mut chunk).unwrap();
+            if bytes == 0 {
+                return out;
+            }
+            out.extend_from_slice(&chunk[..bytes]);
+        }
+    }
+
+    #[test]
+    fn test_reader_matches_encoder() {
+        let data = sample_text().repeat(300);
+
+        for level in [CompressionLevel::NONE, CompressionLevel::DEFAULT] {
+            for chunk_len in [1, 1000, 100_000] {
+                let mut reader = DeflateReader::with_options(&*data, level.into());
+                let compressed = read_in_chunks(&mut reader, chunk_len);
+                assert_eq!(compressed, encode_with_level(&data, level));
+            }
+        }
+
+        let mut reader = DeflateReader::new(io::empty());
+        assert_eq!(decode(&read_in_chunks(&mut reader, 100)), b"");
+    }
+
+    #[test]
+    fn test_reader_buffers_one_block() {
+        let data = pseudorandom_bytes(300_000);
+        let mut reader = DeflateReader::new(&*data);
+
+        let mut byte = [0];
+        assert_eq!(io::Read::read(&mut reader, &mut byte).unwrap(), 1);
+        assert_eq!(reader.get_ref().len(), data.len() - MAX_BYTES_PER_BLOCK - 1);
+    }
+
+    #[test]
+    fn test_reader_dictionary_and_verify() {
+        let dictionary = &sample_text()[..500];
+        let data = sample_text().repeat(10);
+        let options = EncoderOptions {
+            verify: true,
+            ..EncoderOptions::default()
+        };
+
+        let mut reader = DeflateReader::with_dictionary(&*data, options, dictionary).unwrap();
+        let compressed = read_in_chunks(&mut reader, 1000);
+
+        let mut expected = vec![];
+        DeflateEncoder::<HashChain>::with_dictionary(options, dictionary)
+            .unwrap()
+            .encode(&mut &*data, &mut expected)
+            .unwrap();
+        assert_eq!(compressed, expected);
+    }
+}
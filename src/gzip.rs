@@ -1,21 +1,211 @@
-use crate::{bit_io::BitReader, deflate::DeflateDecoder, out_with_checksum::OutWithChecksum};
+use crate::{
+    bit_io::BitReader,
+    deflate::{
+        CompressionLevel, CompressionStats, DeflateDecoder, DeflateEncoder, DeflateReader,
+        EncoderOptions,
+    },
+    out_with_checksum::{InWithChecksum, OutWithChecksum},
+    parallel::{ParallelDeflateEncoder, DEFAULT_CHUNK_LEN},
+};
 use bitvec::prelude::*;
 use std::io;
 
 const GZIP_ID1: u8 = 0x1f;
 const GZIP_ID2: u8 = 0x8b;
 const GZIP_CM_DEFLATE: u8 = 0x08;
+const GZIP_XFL_MAX_COMPRESSION: u8 = 2;
+const GZIP_XFL_FASTEST: u8 = 4;
+const GZIP_OS_UNKNOWN: u8 = 255;
 
-#[derive(Debug)]
+/// The size of a header with no optional fields.
+const GZIP_HEADER_LEN: usize = 10;
+/// The size of the CRC-32 and input size after the compressed data.
+const GZIP_TRAILER_LEN: usize = 8;
+
+#[derive(Debug, Default)]
 enum DecodeStage {
+    #[default]
     NewMember,
     DecodeDeflate,
     Complete,
 }
 
-impl Default for DecodeStage {
-    fn default() -> Self {
-        Self::NewMember
+/// The header of a member with no optional fields, no file name and no
+/// modification time.
+fn header(xfl: u8) -> [u8; GZIP_HEADER_LEN] {
+    let mtime: u32 = 0;
+    let [mtime0, mtime1, mtime2, mtime3] = mtime.to_le_bytes();
+    [
+        GZIP_ID1,
+        GZIP_ID2,
+        GZIP_CM_DEFLATE,
+        0,
+        mtime0,
+        mtime1,
+        mtime2,
+        mtime3,
+        xfl,
+        GZIP_OS_UNKNOWN,
+    ]
+}
+
+/// The XFL field, which records how hard the encoder tried.
+fn xfl(level: CompressionLevel) -> u8 {
+    match level {
+        CompressionLevel::FASTEST => GZIP_XFL_FASTEST,
+        CompressionLevel::BEST => GZIP_XFL_MAX_COMPRESSION,
+        _ => 0,
+    }
+}
+
+/// The CRC-32 and size of the input, which end a member.
+fn trailer(crc32: u32, size: u32) -> [u8; GZIP_TRAILER_LEN] {
+    let mut trailer = [0; GZIP_TRAILER_LEN];
+    trailer[..4].copy_from_slice(&crc32.to_le_bytes());
+    trailer[4..].copy_from_slice(&size.to_le_bytes());
+    trailer
+}
+
+/// Encodes data as a single gzip member, with no file name or modification
+/// time.
+#[derive(Debug, Default)]
+pub struct GzipEncoder {
+    deflate_encoder: DeflateEncoder,
+    xfl: u8,
+}
+
+impl GzipEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_level(level: CompressionLevel) -> Self {
+        Self::with_options(level.into())
+    }
+
+    pub fn with_options(options: EncoderOptions) -> Self {
+        Self {
+            deflate_encoder: DeflateEncoder::with_options(options),
+            xfl: xfl(options.level),
+        }
+    }
+
+    /// Statistics about the Deflate blocks written so far. See
+    /// `DeflateEncoder::stats`.
+    pub fn stats(&self) -> &CompressionStats {
+        self.deflate_encoder.stats()
+    }
+
+    /// The most bytes `encode` can write for `input_len` bytes of input.
+    /// See `DeflateEncoder::compress_bound`.
+    pub fn compress_bound(&self, input_len: usize) -> usize {
+        GZIP_HEADER_LEN + self.deflate_encoder.compress_bound(input_len) + GZIP_TRAILER_LEN
+    }
+
+    pub fn encode<R, W>(&mut self, in_: &mut R, out: &mut W) -> io::Result<()>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        out.write_all(&header(self.xfl))?;
+
+        let mut in_with_checksum = InWithChecksum::new(in_);
+        self.deflate_encoder.encode(&mut in_with_checksum, out)?;
+
+        out.write_all(&trailer(in_with_checksum.crc32(), in_with_checksum.size()))?;
+        out.flush()
+    }
+}
+
+/// Encodes data as a single gzip member on several threads. See
+/// `ParallelDeflateEncoder`.
+#[derive(Clone, Copy, Debug)]
+pub struct ParallelGzipEncoder {
+    deflate_encoder: ParallelDeflateEncoder,
+    xfl: u8,
+}
+
+impl ParallelGzipEncoder {
+    pub fn new(options: EncoderOptions, threads: usize) -> Self {
+        Self::with_chunk_len(options, threads, DEFAULT_CHUNK_LEN)
+    }
+
+    pub fn with_chunk_len(options: EncoderOptions, threads: usize, chunk_len: usize) -> Self {
+        Self {
+            deflate_encoder: ParallelDeflateEncoder::with_chunk_len(options, threads, chunk_len),
+            xfl: xfl(options.level),
+        }
+    }
+
+    pub fn encode<R, W>(&self, in_: &mut R, out: &mut W) -> io::Result<()>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        out.write_all(&header(self.xfl))?;
+        let (crc32, size) = self.deflate_encoder.encode_with_checksum(in_, out)?;
+        out.write_all(&trailer(crc32, size))?;
+        out.flush()
+    }
+}
+
+/// A reader that compresses everything read from an inner reader, and
+/// returns it as a single gzip member. See `DeflateReader`.
+#[derive(Debug)]
+pub struct GzipReader<R> {
+    deflate_reader: DeflateReader<InWithChecksum<R>>,
+    /// The header, and then the trailer once the Deflate stream has been read
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+    is_trailer_buffered: bool,
+}
+
+impl<R> GzipReader<R>
+where
+    R: io::Read,
+{
+    pub fn new(inner: R) -> Self {
+        Self::with_options(inner, EncoderOptions::default())
+    }
+
+    pub fn with_options(inner: R, options: EncoderOptions) -> Self {
+        Self {
+            deflate_reader: DeflateReader::with_options(InWithChecksum::new(inner), options),
+            buffer: header(xfl(options.level)).to_vec(),
+            buffer_pos: 0,
+            is_trailer_buffered: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.deflate_reader.get_ref().get_ref()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.deflate_reader.into_inner().into_inner()
+    }
+}
+
+impl<R> io::Read for GzipReader<R>
+where
+    R: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer_pos == self.buffer.len() && !self.is_trailer_buffered {
+            let bytes = self.deflate_reader.read(buf)?;
+            if bytes > 0 || buf.is_empty() {
+                return Ok(bytes);
+            }
+
+            let in_with_checksum = self.deflate_reader.get_ref();
+            self.buffer = trailer(in_with_checksum.crc32(), in_with_checksum.size()).to_vec();
+            self.buffer_pos = 0;
+            self.is_trailer_buffered = true;
+        }
+
+        let bytes = (&self.buffer[self.buffer_pos..]).read(buf)?;
+        self.buffer_pos += bytes;
+        Ok(bytes)
     }
 }
 
@@ -191,3 +381,126 @@ impl GzipDecoder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::pseudorandom_bytes;
+    use std::io::Read;
+
+    fn decode(compressed: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        GzipDecoder::new()
+            .decode(&mut BitReader::new(&mut &*compressed), &mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"Twinkle, twinkle, little star, how I wonder what you are.\n".repeat(30);
+
+        for level in [CompressionLevel::NONE, CompressionLevel::DEFAULT] {
+            let mut compressed = vec![];
+            GzipEncoder::with_level(level)
+                .encode(&mut &data[..], &mut compressed)
+                .unwrap();
+            assert_eq!(decode(&compressed), data);
+        }
+    }
+
+    #[test]
+    fn test_reader_matches_encoder() {
+        let data = b"Twinkle, twinkle, little star, how I wonder what you are.\n".repeat(30);
+
+        for level in [CompressionLevel::NONE, CompressionLevel::BEST] {
+            let mut expected = vec![];
+            GzipEncoder::with_level(level)
+                .encode(&mut &data[..], &mut expected)
+                .unwrap();
+
+            // Read a byte at a time, to split the header and trailer
+            let mut reader = GzipReader::with_options(&data[..], level.into());
+            let mut compressed = vec![];
+            let mut byte = [0];
+            while reader.read(&mut byte).unwrap() == 1 {
+                compressed.push(byte[0]);
+            }
+            assert_eq!(compressed, expected);
+        }
+    }
+
+    #[test]
+    fn test_rsyncable() {
+        let data = b"Twinkle, twinkle, little star, how I wonder what you are.\n".repeat(300);
+        let options = EncoderOptions {
+            rsyncable: true,
+            ..EncoderOptions::default()
+        };
+
+        let mut compressed = vec![];
+        GzipEncoder::with_options(options)
+            .encode(&mut &data[..], &mut compressed)
+            .unwrap();
+        assert_eq!(decode(&compressed), data);
+
+        let mut read_compressed = vec![];
+        GzipReader::with_options(&data[..], options)
+            .read_to_end(&mut read_compressed)
+            .unwrap();
+        assert_eq!(read_compressed, compressed);
+    }
+
+    #[test]
+    fn test_parallel() {
+        let data = b"Twinkle, twinkle, little star, how I wonder what you are.\n".repeat(300);
+
+        let mut compressed = vec![];
+        ParallelGzipEncoder::with_chunk_len(EncoderOptions::default(), 3, 4000)
+            .encode(&mut &data[..], &mut compressed)
+            .unwrap();
+        assert_eq!(decode(&compressed), data);
+    }
+
+    #[test]
+    fn test_verify_mismatch() {
+        let data = pseudorandom_bytes(10_000);
+        let options = EncoderOptions {
+            verify: true,
+            ..EncoderOptions::default()
+        };
+
+        let mut encoder = GzipEncoder::with_options(options);
+        encoder.deflate_encoder.corrupt_verification();
+        assert!(encoder.encode(&mut &*data, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_compress_bound() {
+        // Incompressible data, so that the bound is reached exactly
+        let data = pseudorandom_bytes(65535);
+
+        // Including input that fills a whole stored block
+        for len in [5000, data.len()] {
+            for level in [CompressionLevel::NONE, CompressionLevel::BEST] {
+                let mut encoder = GzipEncoder::with_level(level);
+                let bound = encoder.compress_bound(len);
+                let mut compressed = vec![];
+                encoder.encode(&mut &data[..len], &mut compressed).unwrap();
+                assert_eq!(compressed.len(), bound);
+            }
+        }
+
+        // Incompressible input with many rsync points
+        let data = pseudorandom_bytes(200_000);
+        let options = EncoderOptions {
+            rsyncable: true,
+            ..EncoderOptions::default()
+        };
+        let mut encoder = GzipEncoder::with_options(options);
+        let bound = encoder.compress_bound(data.len());
+        let mut compressed = vec![];
+        encoder.encode(&mut &*data, &mut compressed).unwrap();
+        assert!(compressed.len() <= bound, "{} > {bound}", compressed.len());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::bit_io::BitReader;
+use crate::bit_io::{BitReader, BitWriter};
 use std::{collections::BTreeMap, io, sync::LazyLock};
 
 static FIXED_LITERAL_CODE_LENGTHS: LazyLock<[u8; 288]> = LazyLock::new(|| {
@@ -8,71 +8,449 @@ static FIXED_LITERAL_CODE_LENGTHS: LazyLock<[u8; 288]> = LazyLock::new(|| {
         .unwrap()
 });
 
+static FIXED_DISTANCE_CODE_LENGTHS: [u8; 30] = [5; 30];
+
+/// The longest code allowed for the literal/length and distance alphabets.
+pub const MAX_CODE_LENGTH: u8 = 15;
+
+/// The longest code allowed for the code length alphabet.
+pub const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 7;
+
 static DYNAMIC_CODE_LENGTH_SYMBOLS: [u8; 19] = [
     16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
 ];
 
-fn compute_heap_index(code: usize, code_len: usize) -> usize {
-    code | (1 << code_len)
+/// Assigns each symbol its canonical Huffman code, following RFC 1951,
+/// section 3.2.2. Symbols with a code length of 0 are given a code of 0.
+fn canonical_codes(code_lengths: &[u8]) -> Vec<u16> {
+    let code_length_counts = code_lengths.iter().filter(|&&length| length > 0).fold(
+        <BTreeMap<_, u16>>::new(),
+        |mut map, &length| {
+            *map.entry(length).or_default() += 1;
+            map
+        },
+    );
+
+    let largest_code_length = code_length_counts
+        .last_key_value()
+        .map_or(0, |(&code_len, _)| code_len);
+
+    let mut next_code = vec![0u16];
+    let mut code = 0u16;
+
+    for length in 1..=largest_code_length {
+        let count = code_length_counts
+            .get(&(length - 1))
+            .copied()
+            .unwrap_or_default();
+        code = (code + count) << 1;
+        next_code.push(code);
+    }
+
+    code_lengths
+        .iter()
+        .map(|&code_len| {
+            if code_len == 0 {
+                return 0;
+            }
+
+            let code_len = usize::from(code_len);
+            let code = next_code[code_len];
+            next_code[code_len] += 1;
+            code
+        })
+        .collect()
 }
 
-#[derive(Debug)]
-pub struct HuffmanTree {
-    /// The Huffman tree, encoded as an array-based heap.
-    /// The root node is at index 1, and children are at 2n and 2n+1.
-    tree: Vec<Option<u16>>,
+/// Computes Huffman code lengths for the given symbol frequencies, limiting
+/// each code to at most `max_code_length` bits.
+///
+/// At least two symbols are always given a code (even if they are unused), as
+/// some decoders reject a code with a single symbol.
+fn code_lengths_from_frequencies(frequencies: &[u32], max_code_length: u8) -> Vec<u8> {
+    let mut symbols = (0..frequencies.len())
+        .filter(|&symbol| frequencies[symbol] > 0)
+        .collect::<Vec<_>>();
+    for (symbol, &frequency) in frequencies.iter().enumerate() {
+        if symbols.len() >= 2 {
+            break;
+        }
+
+        if frequency == 0 {
+            symbols.push(symbol);
+        }
+    }
+    symbols.sort_by_key(|&symbol| (frequencies[symbol], symbol));
+
+    // Build the tree with the two-queue method: leaves are already sorted by
+    // weight, and internal nodes are created in order of increasing weight.
+    let leaf_count = symbols.len();
+    let mut weights = symbols
+        .iter()
+        .map(|&symbol| u64::from(frequencies[symbol]))
+        .collect::<Vec<_>>();
+    let mut parents = vec![0; 2 * leaf_count - 1];
+    let mut next_leaf = 0;
+    let mut next_internal = leaf_count;
+
+    for node in leaf_count..2 * leaf_count - 1 {
+        let mut children = [0; 2];
+        for child in &mut children {
+            let take_leaf = next_leaf < leaf_count
+                && (next_internal == node || weights[next_leaf] <= weights[next_internal]);
+            if take_leaf {
+                *child = next_leaf;
+                next_leaf += 1;
+            } else {
+                *child = next_internal;
+                next_internal += 1;
+            }
+        }
+
+        weights.push(weights[children[0]] + weights[children[1]]);
+        parents[children[0]] = node;
+        parents[children[1]] = node;
+    }
+
+    // Parents always come after their children, so depths can be filled in
+    // from the root downwards.
+    let mut depths = vec![0u8; 2 * leaf_count - 1];
+    for node in (0..2 * leaf_count - 2).rev() {
+        depths[node] = depths[parents[node]] + 1;
+    }
+
+    // Clamp over-long codes to `max_code_length`, then lengthen shorter codes
+    // until the code lengths satisfy the Kraft inequality again.
+    let max_code_length = usize::from(max_code_length);
+    let mut code_length_counts = vec![0u32; max_code_length + 1];
+    for &depth in &depths[..leaf_count] {
+        code_length_counts[usize::from(depth).min(max_code_length)] += 1;
+    }
+
+    let mut kraft_total: u32 = (1..=max_code_length)
+        .map(|code_len| code_length_counts[code_len] << (max_code_length - code_len))
+        .sum();
+    while kraft_total > 1 << max_code_length {
+        code_length_counts[max_code_length] -= 1;
+        for code_len in (1..max_code_length).rev() {
+            if code_length_counts[code_len] > 0 {
+                code_length_counts[code_len] -= 1;
+                code_length_counts[code_len + 1] += 2;
+                break;
+            }
+        }
+        kraft_total -= 1;
+    }
+
+    // Hand out the shortest codes to the most frequent symbols.
+    let mut code_lengths = vec![0; frequencies.len()];
+    let mut symbols_by_frequency = symbols.iter().rev();
+    for (code_len, &count) in code_length_counts.iter().enumerate() {
+        for _ in 0..count {
+            let &symbol = symbols_by_frequency.next().unwrap();
+            code_lengths[symbol] = code_len.try_into().unwrap();
+        }
+    }
+
+    code_lengths
 }
 
-impl HuffmanTree {
-    pub fn from_code_lengths(code_lengths: &[u8]) -> Self {
-        let code_length_counts = code_lengths.iter().filter(|&&length| length > 0).fold(
-            <BTreeMap<_, u16>>::new(),
-            |mut map, &length| {
-                *map.entry(length).or_default() += 1;
-                map
-            },
-        );
+/// Returns the code lengths of the code length alphabet in the order they are
+/// written in a dynamic block header, with trailing zeros trimmed. This is the
+/// inverse of `HuffmanTable::dynamic_code_lengths`.
+pub fn code_lengths_in_symbol_order(code_lengths: &[u8]) -> Vec<u8> {
+    let mut code_lengths_in_symbol_order = DYNAMIC_CODE_LENGTH_SYMBOLS
+        .iter()
+        .map(|&symbol| code_lengths[usize::from(symbol)])
+        .collect::<Vec<_>>();
+
+    // At least 4 code lengths must be written
+    while code_lengths_in_symbol_order.len() > 4 && code_lengths_in_symbol_order.last() == Some(&0)
+    {
+        code_lengths_in_symbol_order.pop();
+    }
 
-        let largest_code_length = code_length_counts
-            .last_key_value()
-            .map_or(0, |(&code_len, _)| code_len);
+    code_lengths_in_symbol_order
+}
+
+/// A symbol of the code length alphabet, along with the value of its extra
+/// bits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CodeLengthSymbol {
+    pub symbol: u8,
+    pub extra_bits: u8,
+}
 
-        let mut next_code = vec![0u16];
-        let mut code = 0u16;
+impl CodeLengthSymbol {
+    fn new(symbol: u8, extra_bits: u8) -> Self {
+        Self { symbol, extra_bits }
+    }
 
-        for length in 1..=largest_code_length {
-            let count = code_length_counts
-                .get(&(length - 1))
-                .copied()
-                .unwrap_or_default();
-            code = (code + count) << 1;
-            next_code.push(code);
+    pub fn extra_bit_count(self) -> u8 {
+        match self.symbol {
+            16 => 2,
+            17 => 3,
+            18 => 7,
+            _ => 0,
         }
+    }
+}
+
+/// Run-length encodes code lengths into the code length alphabet. This is the
+/// inverse of `HuffmanTable::decode_code_lengths`.
+pub fn encode_code_lengths(code_lengths: &[u8]) -> Vec<CodeLengthSymbol> {
+    let mut symbols = vec![];
+
+    let mut idx = 0;
+    while idx < code_lengths.len() {
+        let code_len = code_lengths[idx];
+        let run = code_lengths[idx..]
+            .iter()
+            .take_while(|&&length| length == code_len)
+            .count();
+        idx += run;
+
+        let mut remaining = run;
+        if code_len == 0 {
+            while remaining >= 11 {
+                let repeat = remaining.min(138);
+                symbols.push(CodeLengthSymbol::new(18, (repeat - 11).try_into().unwrap()));
+                remaining -= repeat;
+            }
 
-        let mut tree = vec![None; 1 << (largest_code_length + 1)];
-        for (symbol, &code_len) in code_lengths.iter().enumerate() {
+            if remaining >= 3 {
+                symbols.push(CodeLengthSymbol::new(
+                    17,
+                    (remaining - 3).try_into().unwrap(),
+                ));
+                remaining = 0;
+            }
+        } else {
+            symbols.push(CodeLengthSymbol::new(code_len, 0));
+            remaining -= 1;
+
+            while remaining >= 3 {
+                let repeat = remaining.min(6);
+                symbols.push(CodeLengthSymbol::new(16, (repeat - 3).try_into().unwrap()));
+                remaining -= repeat;
+            }
+        }
+
+        for _ in 0..remaining {
+            symbols.push(CodeLengthSymbol::new(code_len, 0));
+        }
+    }
+
+    symbols
+}
+
+/// The number of bits `HuffmanTable` looks up in its primary table. Longer
+/// codes take a second lookup, in a subtable.
+const PRIMARY_TABLE_BITS: u8 = 10;
+
+static FIXED_LITERAL_LENGTH_TABLE: LazyLock<HuffmanTable> =
+    LazyLock::new(|| HuffmanTable::literal_length(&*FIXED_LITERAL_CODE_LENGTHS).unwrap());
+
+static FIXED_DISTANCE_TABLE: LazyLock<HuffmanTable> =
+    LazyLock::new(|| HuffmanTable::distance(&FIXED_DISTANCE_CODE_LENGTHS).unwrap());
+
+/// The value of each literal/length symbol before its extra bits are added,
+/// and how many extra bits it has. Literals and the end of a block decode to
+/// themselves, and lengths to `length - 3`.
+fn literal_length_base(symbol: u16) -> (u16, u8) {
+    match symbol {
+        0..=256 => (symbol, 0),
+        257..=264 => (symbol - 257, 0),
+        265..=284 => {
+            let code = symbol - 257;
+            let extra_bit_count = code / 4 - 1;
+            (
+                (1 << (code / 4 + 1)) + (1 << extra_bit_count) * (code % 4),
+                extra_bit_count.try_into().unwrap(),
+            )
+        }
+        285 => (255, 0),
+        // Invalid symbols, which the decoder rejects
+        286.. => (0, 0),
+    }
+}
+
+/// The value of each distance symbol before its extra bits are added, and how
+/// many extra bits it has. Distances decode to `distance - 1`.
+fn distance_base(symbol: u16) -> (u16, u8) {
+    match symbol {
+        0..=3 => (symbol, 0),
+        4..=29 => {
+            let extra_bit_count = symbol / 2 - 1;
+            (
+                (1 << (symbol / 2)) + (1 << extra_bit_count) * (symbol % 2),
+                extra_bit_count.try_into().unwrap(),
+            )
+        }
+        // Invalid symbols, which the decoder rejects
+        30.. => (0, 0),
+    }
+}
+
+/// The value of each code length symbol before its extra bits are added,
+/// and how many extra bits it has. Code lengths decode to themselves, and
+/// repeats to their repeat count.
+fn code_length_base(symbol: u16) -> (u16, u8) {
+    match symbol {
+        0..=15 => (symbol, 0),
+        16 => (3, 2),
+        17 => (3, 3),
+        18.. => (11, 7),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+enum TableEntry {
+    /// No code starts with these bits
+    #[default]
+    Invalid,
+
+    /// The code of `symbol`, which is `code_len` bits long. It is followed by
+    /// `extra_bit_count` extra bits, which are added to `base`.
+    Symbol {
+        symbol: u16,
+        base: u16,
+        code_len: u8,
+        extra_bit_count: u8,
+    },
+
+    /// Codes longer than the primary table. The next `bits` bits after the
+    /// primary table's index the subtable at `start`.
+    Subtable { start: u16, bits: u8 },
+}
+
+/// The decoding side of a Huffman code: a table indexed by the next bits of
+/// the input, like zlib's inflate uses. A code is resolved in one lookup, or
+/// two if it is longer than `PRIMARY_TABLE_BITS`, and its extra bits are read
+/// along with it.
+#[derive(Clone, Debug)]
+pub struct HuffmanTable {
+    /// The primary table, followed by the subtables
+    entries: Vec<TableEntry>,
+    primary_bits: u8,
+    /// The most bits a code and its extra bits take
+    max_bits: u8,
+}
+
+impl HuffmanTable {
+    /// Builds the table for a code, where `base` gives the value of each
+    /// symbol and its number of extra bits. Incomplete codes are allowed, but
+    /// decoding fails on the bits that aren't assigned a code.
+    fn new(code_lengths: &[u8], base: impl Fn(u16) -> (u16, u8)) -> io::Result<Self> {
+        let kraft_total: u32 = code_lengths
+            .iter()
+            .filter(|&&code_len| code_len > 0)
+            .map(|&code_len| 1 << (MAX_CODE_LENGTH - code_len))
+            .sum();
+        if kraft_total > 1 << MAX_CODE_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Huffman code lengths are over-subscribed",
+            ));
+        }
+
+        let largest_code_length = code_lengths.iter().copied().max().unwrap_or_default();
+        let primary_bits = largest_code_length.min(PRIMARY_TABLE_BITS);
+        let primary_mask = (1 << primary_bits) - 1;
+
+        // Codes are read starting with their most-significant bit, so tables
+        // are indexed by the reversed codes
+        let reversed_codes = canonical_codes(code_lengths)
+            .into_iter()
+            .zip(code_lengths)
+            .map(|(code, &code_len)| match code_len {
+                0 => 0,
+                _ => usize::from(code.reverse_bits() >> (16 - code_len)),
+            })
+            .collect::<Vec<_>>();
+
+        // Each subtable is big enough for the longest code that starts with
+        // its index in the primary table
+        let mut subtable_bits = vec![0u8; 1 << primary_bits];
+        for (&code_len, &reversed_code) in code_lengths.iter().zip(&reversed_codes) {
+            if code_len > primary_bits {
+                let bits = &mut subtable_bits[reversed_code & primary_mask];
+                *bits = (*bits).max(code_len - primary_bits);
+            }
+        }
+
+        let mut entries = vec![TableEntry::Invalid; 1 << primary_bits];
+        for (idx, &bits) in subtable_bits.iter().enumerate() {
+            if bits > 0 {
+                let start = entries.len().try_into().unwrap();
+                entries[idx] = TableEntry::Subtable { start, bits };
+                entries.resize(entries.len() + (1 << bits), TableEntry::Invalid);
+            }
+        }
+
+        let mut max_bits = 0;
+        for (symbol, (&code_len, &reversed_code)) in
+            code_lengths.iter().zip(&reversed_codes).enumerate()
+        {
             if code_len == 0 {
                 continue;
             }
 
-            let code_len = usize::from(code_len);
-            let code: usize = next_code[code_len].into();
+            let symbol = symbol.try_into().unwrap();
+            let (base, extra_bit_count) = base(symbol);
+            max_bits = max_bits.max(code_len + extra_bit_count);
+
+            let (table_start, table_bits, idx, idx_bits) = if code_len <= primary_bits {
+                (0, primary_bits, reversed_code, code_len)
+            } else {
+                let TableEntry::Subtable { start, bits } = entries[reversed_code & primary_mask]
+                else {
+                    unreachable!("every long code has a subtable");
+                };
+                (
+                    usize::from(start),
+                    bits,
+                    reversed_code >> primary_bits,
+                    code_len - primary_bits,
+                )
+            };
+
+            // The bits after the code don't matter, so the code fills every
+            // entry that starts with it
+            for idx in (idx..1 << table_bits).step_by(1 << idx_bits) {
+                entries[table_start + idx] = TableEntry::Symbol {
+                    symbol,
+                    base,
+                    code_len,
+                    extra_bit_count,
+                };
+            }
+        }
 
-            let heap_index = compute_heap_index(code, code_len);
-            let heap_symbol: u16 = symbol.try_into().unwrap();
-            tree[heap_index] = Some(heap_symbol);
+        Ok(Self {
+            entries,
+            primary_bits,
+            max_bits,
+        })
+    }
 
-            next_code[code_len] += 1;
-        }
+    pub fn literal_length(code_lengths: &[u8]) -> io::Result<Self> {
+        Self::new(code_lengths, literal_length_base)
+    }
 
-        Self { tree }
+    pub fn distance(code_lengths: &[u8]) -> io::Result<Self> {
+        Self::new(code_lengths, distance_base)
     }
 
-    pub fn fixed_literal() -> Self {
-        Self::from_code_lengths(&*FIXED_LITERAL_CODE_LENGTHS)
+    pub fn fixed_literal_length() -> &'static Self {
+        &FIXED_LITERAL_LENGTH_TABLE
     }
 
-    pub fn dynamic_code_lengths(code_lengths_in_symbol_order: &[u8]) -> Self {
+    pub fn fixed_distance() -> &'static Self {
+        &FIXED_DISTANCE_TABLE
+    }
+
+    pub fn dynamic_code_lengths(code_lengths_in_symbol_order: &[u8]) -> io::Result<Self> {
         assert!(code_lengths_in_symbol_order.len() <= DYNAMIC_CODE_LENGTH_SYMBOLS.len());
 
         let mut code_lengths = [0; DYNAMIC_CODE_LENGTH_SYMBOLS.len()];
@@ -83,39 +461,53 @@ impl HuffmanTree {
             code_lengths[usize::from(symbol)] = code_length;
         }
 
-        Self::from_code_lengths(&code_lengths)
+        Self::new(&code_lengths, code_length_base)
     }
 
-    pub fn decode<R>(&self, in_: &mut BitReader<R>) -> io::Result<u16>
+    /// Reads a symbol and its extra bits, and returns the symbol along with
+    /// its value.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn decode<R>(&self, in_: &mut BitReader<R>) -> io::Result<(u16, u16)>
     where
         R: io::Read,
     {
-        let mut index = 1;
-        loop {
-            let bit = in_.read_bool()?;
-            index = 2 * index + usize::from(bit);
-
-            if index >= self.tree.len() {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!(
-                        "invalid Huffman code, got index {index} which is >= {len}",
-                        len = self.tree.len()
-                    ),
-                ));
-            }
+        let bits = in_.peek_bits(self.max_bits.into())?;
 
-            if let Some(symbol) = self.tree[index] {
-                return Ok(symbol);
-            }
+        let mut entry = self.entries[bits as usize & ((1 << self.primary_bits) - 1)];
+        if let TableEntry::Subtable {
+            start,
+            bits: subtable_bits,
+        } = entry
+        {
+            let idx = (bits >> self.primary_bits) as usize & ((1 << subtable_bits) - 1);
+            entry = self.entries[usize::from(start) + idx];
         }
+
+        let TableEntry::Symbol {
+            symbol,
+            base,
+            code_len,
+            extra_bit_count,
+        } = entry
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid Huffman code",
+            ));
+        };
+
+        in_.consume((code_len + extra_bit_count).into())?;
+        let extra_bits = (bits >> code_len) & ((1 << extra_bit_count) - 1);
+        Ok((symbol, base + extra_bits as u16))
     }
 
+    /// Reads `code_length_count` code lengths, run-length encoded with this
+    /// code length code.
     pub fn decode_code_lengths<R>(
         &self,
         code_length_count: usize,
         in_: &mut BitReader<R>,
-    ) -> io::Result<Self>
+    ) -> io::Result<Vec<u8>>
     where
         R: io::Read,
     {
@@ -123,14 +515,14 @@ impl HuffmanTree {
         let mut prev_code_length = None;
 
         while code_lengths.len() < code_length_count {
-            let symbol: u8 = self.decode(in_)?.try_into().unwrap();
+            let (symbol, value) = self.decode(in_)?;
+            let value: u8 = value.try_into().unwrap();
             match symbol {
                 0..=15 => {
-                    code_lengths.push(symbol);
-                    prev_code_length = Some(symbol);
+                    code_lengths.push(value);
+                    prev_code_length = Some(value);
                 }
                 16 => {
-                    let repeat = in_.read_u8_from_bits(2)? + 3;
                     let Some(prev_code_length) = prev_code_length else {
                         return Err(io::Error::new(
                             io::ErrorKind::InvalidData,
@@ -138,17 +530,10 @@ impl HuffmanTree {
                         ));
                     };
 
-                    code_lengths.resize(code_lengths.len() + usize::from(repeat), prev_code_length);
+                    code_lengths.resize(code_lengths.len() + usize::from(value), prev_code_length);
                 }
-                17 => {
-                    let repeat = in_.read_u8_from_bits(3)? + 3;
-                    code_lengths.resize(code_lengths.len() + usize::from(repeat), 0);
-
-                    prev_code_length = Some(0);
-                }
-                18 => {
-                    let repeat = in_.read_u8_from_bits(7)? + 11;
-                    code_lengths.resize(code_lengths.len() + usize::from(repeat), 0);
+                17 | 18 => {
+                    code_lengths.resize(code_lengths.len() + usize::from(value), 0);
 
                     prev_code_length = Some(0);
                 }
@@ -171,32 +556,73 @@ impl HuffmanTree {
             ));
         }
 
-        Ok(Self::from_code_lengths(&code_lengths))
+        Ok(code_lengths)
     }
 }
 
-// TODO: Perhaps restrict `HuffmanTree` in `Dynamic` to u8
+/// The encoding side of a Huffman code: the code assigned to each symbol.
 #[derive(Debug)]
-pub enum DistanceEncoding {
-    Fixed,
-    Dynamic(HuffmanTree),
+pub struct HuffmanCode {
+    /// The code of each symbol, with its bits reversed so that it can be
+    /// written least-significant bit first.
+    reversed_codes: Vec<u16>,
+    code_lengths: Vec<u8>,
 }
 
-impl DistanceEncoding {
-    pub fn decode<R>(&self, in_: &mut BitReader<R>) -> io::Result<u16>
+impl HuffmanCode {
+    pub fn from_code_lengths(code_lengths: &[u8]) -> Self {
+        let reversed_codes = canonical_codes(code_lengths)
+            .into_iter()
+            .zip(code_lengths)
+            .map(|(code, &code_len)| match code_len {
+                0 => 0,
+                _ => code.reverse_bits() >> (16 - code_len),
+            })
+            .collect();
+
+        Self {
+            reversed_codes,
+            code_lengths: code_lengths.to_vec(),
+        }
+    }
+
+    pub fn from_frequencies(frequencies: &[u32], max_code_length: u8) -> Self {
+        Self::from_code_lengths(&code_lengths_from_frequencies(frequencies, max_code_length))
+    }
+
+    pub fn fixed_literal() -> Self {
+        Self::from_code_lengths(&*FIXED_LITERAL_CODE_LENGTHS)
+    }
+
+    pub fn fixed_distance() -> Self {
+        Self::from_code_lengths(&FIXED_DISTANCE_CODE_LENGTHS)
+    }
+
+    pub fn code_lengths(&self) -> &[u8] {
+        &self.code_lengths
+    }
+
+    pub fn write_symbol<W>(&self, symbol: u16, out: &mut BitWriter<W>) -> io::Result<()>
     where
-        R: io::Read,
+        W: io::Write,
     {
-        match self {
-            Self::Fixed => in_.read_u16_from_bits(5),
-            Self::Dynamic(tree) => tree.decode(in_),
+        let symbol = usize::from(symbol);
+        let code_len = self.code_lengths.get(symbol).copied().unwrap_or_default();
+        if code_len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("symbol {symbol} has no Huffman code"),
+            ));
         }
+
+        out.write_u16_from_bits(self.reversed_codes[symbol], code_len.into())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lzss::Symbol;
     use bitvec::prelude::*;
 
     fn literal_bits(literal: u16, bit_len: usize) -> BitVec<u16, Lsb0> {
@@ -204,32 +630,193 @@ mod tests {
         vec.reverse();
 
         // Pad to a multiple of 8 so `.read()` will return the last (possibly
-        // partial) byte
-        vec.resize(((bit_len - 1) / 8 + 1) * 8, false);
+        // partial) byte, with room for the extra bits of a length code
+        vec.resize((bit_len / 8 + 2) * 8, false);
         vec
     }
 
     fn assert_decode(
-        tree: &HuffmanTree,
+        table: &HuffmanTable,
         bit_len: usize,
         literals: impl Iterator<Item = u16>,
         symbols: impl Iterator<Item = u16>,
     ) {
         for (literal, symbol) in literals.zip(symbols) {
             assert_eq!(
-                tree.decode(&mut BitReader::new(&mut literal_bits(literal, bit_len)))
-                    .unwrap(),
+                table
+                    .decode(&mut BitReader::new(&mut literal_bits(literal, bit_len)))
+                    .unwrap()
+                    .0,
                 symbol,
             );
         }
     }
 
+    fn kraft_sum(code_lengths: &[u8]) -> f64 {
+        code_lengths
+            .iter()
+            .filter(|&&code_len| code_len > 0)
+            .map(|&code_len| 0.5f64.powi(code_len.into()))
+            .sum()
+    }
+
+    #[test]
+    fn test_code_lengths_from_frequencies() {
+        let code_lengths = code_lengths_from_frequencies(&[10, 0, 5, 5, 20], MAX_CODE_LENGTH);
+        assert_eq!(code_lengths, [2, 0, 3, 3, 1]);
+
+        let code_lengths = code_lengths_from_frequencies(&[0, 0, 7, 0], MAX_CODE_LENGTH);
+        assert_eq!(code_lengths, [1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_code_lengths_from_frequencies_are_length_limited() {
+        // Fibonacci frequencies produce the deepest possible Huffman tree
+        let mut frequencies = vec![1u32, 1];
+        while frequencies.len() < 30 {
+            frequencies
+                .push(frequencies[frequencies.len() - 2] + frequencies[frequencies.len() - 1]);
+        }
+
+        for max_code_length in [MAX_CODE_LENGTH_CODE_LENGTH, MAX_CODE_LENGTH] {
+            let code_lengths = code_lengths_from_frequencies(&frequencies, max_code_length);
+            assert!(code_lengths
+                .iter()
+                .all(|&code_len| (1..=max_code_length).contains(&code_len)));
+            assert!((kraft_sum(&code_lengths) - 1.0).abs() < f64::EPSILON);
+        }
+    }
+
+    #[test]
+    fn test_encode_code_lengths() {
+        let code_lengths = [[3u8; 8].as_slice(), &[0; 150], &[5; 3], &[0; 4], &[2; 2]].concat();
+        let symbols = encode_code_lengths(&code_lengths);
+
+        let mut decoded = vec![];
+        for symbol in symbols {
+            match symbol.symbol {
+                0..=15 => decoded.push(symbol.symbol),
+                16 => {
+                    let prev = *decoded.last().unwrap();
+                    decoded.resize(decoded.len() + usize::from(symbol.extra_bits) + 3, prev);
+                }
+                17 => decoded.resize(decoded.len() + usize::from(symbol.extra_bits) + 3, 0),
+                18 => decoded.resize(decoded.len() + usize::from(symbol.extra_bits) + 11, 0),
+                19.. => unreachable!(),
+            }
+        }
+
+        assert_eq!(decoded, code_lengths);
+    }
+
     #[test]
     fn test_fixed_literal_huffman() {
-        let tree = HuffmanTree::fixed_literal();
-        assert_decode(&tree, 8, 0b00110000..=0b10111111, 0..=143);
-        assert_decode(&tree, 9, 0b110010000..=0b111111111, 144..=255);
-        assert_decode(&tree, 7, 0b0000000..=0b0010111, 256..=279);
-        assert_decode(&tree, 8, 0b11000000..=0b11000111, 280..=287);
+        let table = HuffmanTable::fixed_literal_length();
+        assert_decode(table, 8, 0b00110000..=0b10111111, 0..=143);
+        assert_decode(table, 9, 0b110010000..=0b111111111, 144..=255);
+        assert_decode(table, 7, 0b0000000..=0b0010111, 256..=279);
+        assert_decode(table, 8, 0b11000000..=0b11000111, 280..=287);
+    }
+
+    /// Writes each symbol with `code`, followed by as many ones as its extra bits, and
+    /// checks that `table` decodes them.
+    fn assert_round_trip(code: &HuffmanCode, table: &HuffmanTable, extra_bit_counts: &[u8]) {
+        let symbols = (0..code.code_lengths().len())
+            .filter(|&symbol| code.code_lengths()[symbol] > 0)
+            .map(|symbol| u16::try_from(symbol).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut out = BitWriter::new(vec![]);
+        for &symbol in &symbols {
+            code.write_symbol(symbol, &mut out).unwrap();
+            let extra_bit_count = extra_bit_counts[usize::from(symbol)];
+            out.write_u16_from_bits(u16::MAX, extra_bit_count.into())
+                .unwrap();
+        }
+        out.flush_even_if_partial().unwrap();
+
+        let compressed = out.into_inner();
+        let mut in_ = &compressed[..];
+        let mut in_ = BitReader::new(&mut in_);
+        for &symbol in &symbols {
+            let extra_bit_count = extra_bit_counts[usize::from(symbol)];
+            assert_eq!(
+                table.decode(&mut in_).unwrap().0,
+                symbol,
+                "symbol {symbol} with {extra_bit_count} extra bits",
+            );
+        }
+    }
+
+    #[test]
+    fn test_long_codes_use_subtables() {
+        // One code of each length, so that most of them need a subtable
+        let code_lengths = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 15];
+        let table = HuffmanTable::distance(&code_lengths).unwrap();
+        assert!(table.entries.len() > 1 << PRIMARY_TABLE_BITS);
+
+        let extra_bit_counts = (0..16)
+            .map(|symbol| distance_base(symbol).1)
+            .collect::<Vec<_>>();
+        assert_round_trip(
+            &HuffmanCode::from_code_lengths(&code_lengths),
+            &table,
+            &extra_bit_counts,
+        );
+    }
+
+    #[test]
+    fn test_decode_extra_bits() {
+        let table = HuffmanTable::fixed_literal_length();
+        let code = HuffmanCode::fixed_literal();
+
+        for length_minus_three in 0..=255 {
+            let length_code = Symbol::back_reference_length_code(length_minus_three);
+            let extra_bit_count = Symbol::back_reference_length_extra_bits(length_minus_three);
+
+            let mut out = BitWriter::new(vec![]);
+            code.write_symbol(length_code, &mut out).unwrap();
+            let extra_bits = u16::from(length_minus_three) & ((1 << extra_bit_count) - 1);
+            out.write_u16_from_bits(extra_bits, extra_bit_count.into())
+                .unwrap();
+            out.flush_even_if_partial().unwrap();
+
+            let compressed = out.into_inner();
+            let decoded = table
+                .decode(&mut BitReader::new(&mut &compressed[..]))
+                .unwrap();
+            assert_eq!(decoded, (length_code, length_minus_three.into()));
+        }
+    }
+
+    #[test]
+    fn test_incomplete_and_over_subscribed_codes() {
+        // A single code of length 1, as used for a block with one distance
+        let table = HuffmanTable::distance(&[0, 1]).unwrap();
+        assert_eq!(
+            table.decode(&mut BitReader::new(&mut &[0][..])).unwrap().0,
+            1
+        );
+        let err = table
+            .decode(&mut BitReader::new(&mut &[1][..]))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // No codes at all
+        let table = HuffmanTable::distance(&[0; 30]).unwrap();
+        assert!(table.decode(&mut BitReader::new(&mut &[0][..])).is_err());
+
+        assert!(HuffmanTable::distance(&[1, 1, 1]).is_err());
+    }
+
+    #[test]
+    fn test_decode_truncated_code() {
+        let table = HuffmanTable::fixed_literal_length();
+
+        // The 9-bit code of 255 is cut off after 8 bits
+        let err = table
+            .decode(&mut BitReader::new(&mut &[0xff][..]))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
     }
 }
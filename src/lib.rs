@@ -4,7 +4,19 @@ mod gzip;
 mod huffman;
 mod lzss;
 mod out_with_checksum;
+mod parallel;
+#[cfg(test)]
+mod test_utils;
+mod verify;
+mod zlib;
 
 pub use bit_io::{BitReader, BitWriter};
-pub use deflate::{DeflateDecoder, DeflateEncoder};
-pub use gzip::GzipDecoder;
+pub use deflate::{
+    BlockStats, CodeLengthHistograms, CompressionLevel, CompressionStats, DecodedItem,
+    DeflateDecoder, DeflateEncoder, DeflateEncoding, DeflateReader, DeflateWriter, EncoderOptions,
+    MemLevel, Strategy, Symbols, WindowBits,
+};
+pub use gzip::{GzipDecoder, GzipEncoder, GzipReader, ParallelGzipEncoder};
+pub use lzss::{BinaryTree, HashChain, Match, MatchFinder, Symbol};
+pub use parallel::ParallelDeflateEncoder;
+pub use zlib::{ZlibDecoder, ZlibEncoder, ZlibReader};
@@ -1,8 +1,14 @@
 use std::collections::VecDeque;
 
-const MAX_DISTANCE_BYTES: usize = 32768;
+pub const MAX_DISTANCE_BYTES: usize = 32768;
 
-#[derive(Debug)]
+/// The shortest back-reference that can be encoded.
+pub const MIN_MATCH_LENGTH: usize = 3;
+
+/// The longest back-reference that can be encoded.
+pub const MAX_MATCH_LENGTH: usize = 258;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Symbol {
     /// A literal byte
     Literal(u8),
@@ -10,14 +16,14 @@ pub enum Symbol {
     /// End of a block
     EndOfBlock,
 
-    /// A back-reference
+    /// A back-reference, repeating `length_minus_three + 3` bytes starting
+    /// `distance_minus_one + 1` bytes back
     BackReference {
         length_minus_three: u8,
         distance_minus_one: u16,
     },
 }
 
-#[allow(dead_code)]
 impl Symbol {
     pub fn length_code(&self) -> u16 {
         match self {
@@ -67,6 +73,715 @@ impl Symbol {
             32768.. => panic!("Distance cannot be more than {MAX_DISTANCE_BYTES}"),
         }
     }
+
+    /// The number of bytes this symbol decodes to.
+    pub fn decoded_len(&self) -> usize {
+        match *self {
+            Self::Literal(_) => 1,
+            Self::EndOfBlock => 0,
+            Self::BackReference {
+                length_minus_three, ..
+            } => usize::from(length_minus_three) + MIN_MATCH_LENGTH,
+        }
+    }
+
+    /// The number of extra bits written after this symbol's codes.
+    pub fn extra_bit_count(&self) -> u8 {
+        match *self {
+            Self::Literal(_) | Self::EndOfBlock => 0,
+            Self::BackReference {
+                length_minus_three,
+                distance_minus_one,
+            } => {
+                Self::back_reference_length_extra_bits(length_minus_three)
+                    + Self::back_reference_distance_extra_bits(distance_minus_one)
+            }
+        }
+    }
+}
+
+/// A repeat of `length` bytes starting `distance` bytes back.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Match {
+    pub length: usize,
+    pub distance: usize,
+}
+
+impl From<Match> for Symbol {
+    fn from(Match { length, distance }: Match) -> Self {
+        Self::BackReference {
+            length_minus_three: (length - MIN_MATCH_LENGTH).try_into().unwrap(),
+            distance_minus_one: (distance - 1).try_into().unwrap(),
+        }
+    }
+}
+
+/// Finds earlier occurrences of the bytes at a position, for the parsers in
+/// this module.
+///
+/// Positions are indices into the `data` slice passed to each method, which
+/// must be the same (or an extension of the same) slice between calls to
+/// `reset`. Positions are searched and inserted in increasing order, and a
+/// position is always searched before it is inserted, if at all.
+pub trait MatchFinder {
+    /// The furthest back a match can be.
+    fn window_size(&self) -> usize;
+
+    /// The most earlier positions to search for a match.
+    fn max_chain_length(&self) -> usize;
+
+    /// Forgets every inserted position.
+    fn reset(&mut self);
+
+    /// Makes `pos` available as a match for later positions.
+    fn insert(&mut self, data: &[u8], pos: usize);
+
+    /// Finds the longest match for the bytes at `pos`, not extending past
+    /// `end`, searching at most `max_chain_length` earlier positions.
+    fn longest_match_within_chain(
+        &mut self,
+        data: &[u8],
+        pos: usize,
+        end: usize,
+        max_chain_length: usize,
+    ) -> Option<Match>;
+
+    /// Finds every match for the bytes at `pos` that is longer than all
+    /// closer matches, in order of increasing length and distance.
+    fn matches(&mut self, data: &[u8], pos: usize, end: usize) -> Vec<Match>;
+
+    /// Like `longest_match_within_chain`, searching `max_chain_length`
+    /// earlier positions.
+    fn longest_match(&mut self, data: &[u8], pos: usize, end: usize) -> Option<Match> {
+        let max_chain_length = self.max_chain_length();
+        self.longest_match_within_chain(data, pos, end, max_chain_length)
+    }
+}
+
+/// A position that hasn't been inserted into a match finder.
+const NONE: u32 = u32::MAX;
+
+/// Hashes the `MIN_MATCH_LENGTH` bytes at `pos` into `hash_bits` bits.
+fn hash(data: &[u8], pos: usize, hash_bits: u32) -> usize {
+    let bytes = u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], 0]);
+    (bytes.wrapping_mul(0x9e37_79b1) >> (32 - hash_bits)) as usize
+}
+
+/// Finds matches by chaining together every earlier position whose next
+/// `MIN_MATCH_LENGTH` bytes share a hash. This is what zlib does.
+#[derive(Debug)]
+pub struct HashChain {
+    /// The most recent position inserted for each hash.
+    head: Vec<u32>,
+    /// The previous position with the same hash, indexed by position modulo
+    /// `window_size`.
+    prev: Vec<u32>,
+    hash_bits: u32,
+    /// Matches further back than this are ignored.
+    window_size: usize,
+    /// Matches shorter than this are ignored.
+    min_length: usize,
+    max_chain_length: usize,
+    nice_length: usize,
+}
+
+impl HashChain {
+    /// Creates a match finder with a table of `2^hash_bits` chains, that
+    /// finds matches up to `window_size` bytes back.
+    pub fn new(
+        hash_bits: u32,
+        window_size: usize,
+        min_length: usize,
+        max_chain_length: usize,
+        nice_length: usize,
+    ) -> Self {
+        assert!(min_length >= MIN_MATCH_LENGTH);
+        assert!(window_size <= MAX_DISTANCE_BYTES);
+
+        Self {
+            head: vec![NONE; 1 << hash_bits],
+            prev: vec![NONE; window_size],
+            hash_bits,
+            window_size,
+            min_length,
+            max_chain_length,
+            nice_length,
+        }
+    }
+
+    /// Walks the chain of earlier positions for `pos`, calling `on_match`
+    /// whenever a match longer than all previous ones is found.
+    fn walk_chain(
+        &self,
+        data: &[u8],
+        pos: usize,
+        end: usize,
+        max_chain_length: usize,
+        mut on_match: impl FnMut(Match),
+    ) {
+        let max_length = (end - pos).min(MAX_MATCH_LENGTH);
+        if max_length < self.min_length {
+            return;
+        }
+
+        let mut best_length = self.min_length - 1;
+        let mut candidate = self.head[hash(data, pos, self.hash_bits)];
+
+        for _ in 0..max_chain_length {
+            if candidate == NONE {
+                break;
+            }
+
+            let candidate_pos = candidate as usize;
+            let distance = pos - candidate_pos;
+            if distance == 0 || distance > self.window_size {
+                break;
+            }
+
+            if data[candidate_pos + best_length] == data[pos + best_length] {
+                let length = data[candidate_pos..]
+                    .iter()
+                    .zip(&data[pos..pos + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                if length > best_length {
+                    best_length = length;
+                    on_match(Match { length, distance });
+                    if length >= self.nice_length.min(max_length) {
+                        break;
+                    }
+                }
+            }
+
+            let next = self.prev[candidate_pos % self.window_size];
+            // Older positions may have been overwritten by newer ones
+            if next != NONE && next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+    }
+}
+
+impl MatchFinder for HashChain {
+    fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    fn max_chain_length(&self) -> usize {
+        self.max_chain_length
+    }
+
+    fn reset(&mut self) {
+        self.head.fill(NONE);
+        self.prev.fill(NONE);
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH_LENGTH > data.len() {
+            return;
+        }
+
+        let hash = hash(data, pos, self.hash_bits);
+        self.prev[pos % self.window_size] = self.head[hash];
+        self.head[hash] = pos.try_into().unwrap();
+    }
+
+    fn longest_match_within_chain(
+        &mut self,
+        data: &[u8],
+        pos: usize,
+        end: usize,
+        max_chain_length: usize,
+    ) -> Option<Match> {
+        let mut best = None;
+        self.walk_chain(data, pos, end, max_chain_length, |match_| {
+            best = Some(match_)
+        });
+        best
+    }
+
+    fn matches(&mut self, data: &[u8], pos: usize, end: usize) -> Vec<Match> {
+        let mut matches = vec![];
+        self.walk_chain(data, pos, end, self.max_chain_length, |match_| {
+            matches.push(match_);
+        });
+        matches
+    }
+}
+
+/// Finds matches by keeping the earlier positions with each hash in a binary
+/// search tree, ordered by the bytes that follow them, like LZMA's `bt4`
+/// match finder. Searching only follows the path that shares the most bytes
+/// with the current position, so this finds long matches in repetitive data
+/// in far fewer steps than `HashChain`.
+///
+/// Searching a position also inserts it, so it costs the same as `insert`.
+#[derive(Debug)]
+pub struct BinaryTree {
+    /// The root of the tree for each hash, which is its most recent position.
+    head: Vec<u32>,
+    /// The children of each position, indexed by `slot`. The first child is
+    /// the root of a subtree of positions followed by smaller bytes, and the
+    /// second by larger bytes. Children are always older than their parents.
+    children: Vec<u32>,
+    hash_bits: u32,
+    /// Matches further back than this are ignored.
+    window_size: usize,
+    /// Matches shorter than this are ignored.
+    min_length: usize,
+    /// The deepest the tree is searched.
+    max_chain_length: usize,
+    /// Bytes are only compared this far when ordering the tree, and matches
+    /// this long are taken immediately.
+    nice_length: usize,
+    /// Every position before this has been inserted.
+    next_pos: usize,
+}
+
+impl BinaryTree {
+    /// Creates a match finder with a table of `2^hash_bits` trees, that finds
+    /// matches up to `window_size` bytes back.
+    pub fn new(
+        hash_bits: u32,
+        window_size: usize,
+        min_length: usize,
+        max_chain_length: usize,
+        nice_length: usize,
+    ) -> Self {
+        assert!(min_length >= MIN_MATCH_LENGTH);
+        assert!(window_size <= MAX_DISTANCE_BYTES);
+
+        Self {
+            head: vec![NONE; 1 << hash_bits],
+            children: vec![NONE; 2 * (window_size + 1)],
+            hash_bits,
+            window_size,
+            min_length,
+            max_chain_length,
+            nice_length,
+            next_pos: 0,
+        }
+    }
+
+    /// The index of the children of `pos`. Positions up to `window_size`
+    /// apart have different children.
+    fn slot(&self, pos: usize) -> usize {
+        2 * (pos % (self.window_size + 1))
+    }
+
+    /// Inserts `pos` as the root of its tree, splitting the old tree into
+    /// the new root's subtrees. This walks the same path as a search, so
+    /// `on_match` is called whenever a match longer than all previous ones
+    /// is found.
+    fn insert_and_search(
+        &mut self,
+        data: &[u8],
+        pos: usize,
+        end: usize,
+        max_depth: usize,
+        mut on_match: impl FnMut(Match),
+    ) {
+        if pos < self.next_pos || pos + MIN_MATCH_LENGTH > data.len() {
+            return;
+        }
+        self.next_pos = pos + 1;
+
+        let hash = hash(data, pos, self.hash_bits);
+        let mut candidate = self.head[hash];
+        self.head[hash] = pos.try_into().unwrap();
+
+        let max_length = (end - pos).min(MAX_MATCH_LENGTH);
+        let compare_length = max_length.min(self.nice_length);
+
+        // The child slots still waiting for a subtree of smaller and larger
+        // positions, and how many bytes every position in them shares with
+        // `pos`
+        let slot = self.slot(pos);
+        let (mut smaller_slot, mut larger_slot) = (slot, slot + 1);
+        let (mut smaller_length, mut larger_length) = (0, 0);
+
+        let mut best_length = self.min_length - 1;
+
+        for _ in 0..max_depth {
+            if candidate == NONE {
+                break;
+            }
+
+            let candidate_pos = candidate as usize;
+            let distance = pos - candidate_pos;
+            if distance == 0 || distance > self.window_size {
+                break;
+            }
+
+            let candidate_slot = self.slot(candidate_pos);
+
+            let mut length = smaller_length.min(larger_length);
+            length += data[candidate_pos + length..]
+                .iter()
+                .zip(&data[pos + length..pos + compare_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            // The tree is only ordered as far as `nice_length`, but matches
+            // can be longer
+            let match_length = if length == compare_length {
+                length
+                    + data[candidate_pos + length..]
+                        .iter()
+                        .zip(&data[pos + length..pos + max_length])
+                        .take_while(|(a, b)| a == b)
+                        .count()
+            } else {
+                length
+            };
+
+            if match_length > best_length {
+                best_length = match_length;
+                on_match(Match {
+                    length: match_length,
+                    distance,
+                });
+            }
+
+            if length == compare_length {
+                // The candidate is equal to `pos` as far as the tree is
+                // ordered, so `pos` takes its place
+                self.children[smaller_slot] = self.children[candidate_slot];
+                self.children[larger_slot] = self.children[candidate_slot + 1];
+                return;
+            }
+
+            if data[candidate_pos + length] < data[pos + length] {
+                // The candidate and its smaller subtree go on the smaller
+                // side of `pos`, and its larger subtree still needs sorting
+                self.children[smaller_slot] = candidate;
+                smaller_slot = candidate_slot + 1;
+                smaller_length = length;
+                candidate = self.children[smaller_slot];
+            } else {
+                self.children[larger_slot] = candidate;
+                larger_slot = candidate_slot;
+                larger_length = length;
+                candidate = self.children[larger_slot];
+            }
+        }
+
+        self.children[smaller_slot] = NONE;
+        self.children[larger_slot] = NONE;
+    }
+}
+
+impl MatchFinder for BinaryTree {
+    fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    fn max_chain_length(&self) -> usize {
+        self.max_chain_length
+    }
+
+    fn reset(&mut self) {
+        self.head.fill(NONE);
+        self.children.fill(NONE);
+        self.next_pos = 0;
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        let max_chain_length = self.max_chain_length;
+        self.insert_and_search(data, pos, data.len(), max_chain_length, |_| {});
+    }
+
+    fn longest_match_within_chain(
+        &mut self,
+        data: &[u8],
+        pos: usize,
+        end: usize,
+        max_chain_length: usize,
+    ) -> Option<Match> {
+        let mut best = None;
+        self.insert_and_search(data, pos, end, max_chain_length, |match_| {
+            best = Some(match_)
+        });
+        best
+    }
+
+    fn matches(&mut self, data: &[u8], pos: usize, end: usize) -> Vec<Match> {
+        let mut matches = vec![];
+        let max_chain_length = self.max_chain_length;
+        self.insert_and_search(data, pos, end, max_chain_length, |match_| {
+            matches.push(match_);
+        });
+        matches
+    }
+}
+
+fn insert_history<M>(match_finder: &mut M, data: &[u8], start: usize)
+where
+    M: MatchFinder,
+{
+    match_finder.reset();
+    for pos in start.saturating_sub(match_finder.window_size())..start {
+        match_finder.insert(data, pos);
+    }
+}
+
+/// Parses `data[start..]` into literals and back-references, taking the
+/// longest match at each position. Earlier bytes in `data` are used as
+/// history that back-references can reach into.
+///
+/// To save time, the positions inside matches longer than
+/// `max_insert_length` are not made available to later matches.
+pub fn greedy_parse<M>(
+    match_finder: &mut M,
+    data: &[u8],
+    start: usize,
+    max_insert_length: usize,
+) -> Vec<Symbol>
+where
+    M: MatchFinder,
+{
+    insert_history(match_finder, data, start);
+
+    let mut symbols = vec![];
+    let mut pos = start;
+    while pos < data.len() {
+        match match_finder.longest_match(data, pos, data.len()) {
+            Some(match_) => {
+                symbols.push(match_.into());
+                if match_.length <= max_insert_length {
+                    for insert_pos in pos..pos + match_.length {
+                        match_finder.insert(data, insert_pos);
+                    }
+                } else {
+                    match_finder.insert(data, pos);
+                }
+                pos += match_.length;
+            }
+            None => {
+                symbols.push(Symbol::Literal(data[pos]));
+                match_finder.insert(data, pos);
+                pos += 1;
+            }
+        }
+    }
+
+    symbols
+}
+
+/// Parses `data[start..]` into literals and runs of the previous byte, which
+/// are back-references with a distance of 1.
+pub fn rle_parse(data: &[u8], start: usize) -> Vec<Symbol> {
+    let mut symbols = vec![];
+
+    let mut pos = start;
+    while pos < data.len() {
+        let run_length = match pos.checked_sub(1) {
+            Some(prev_pos) => data[pos..]
+                .iter()
+                .take(MAX_MATCH_LENGTH)
+                .take_while(|&&byte| byte == data[prev_pos])
+                .count(),
+            None => 0,
+        };
+
+        if run_length >= MIN_MATCH_LENGTH {
+            symbols.push(
+                Match {
+                    length: run_length,
+                    distance: 1,
+                }
+                .into(),
+            );
+            pos += run_length;
+        } else {
+            symbols.push(Symbol::Literal(data[pos]));
+            pos += 1;
+        }
+    }
+
+    symbols
+}
+
+/// Parses `data[start..]` like `greedy_parse`, except that a match is only
+/// taken if the next position doesn't start a longer one. Otherwise, a
+/// literal is emitted and the longer match is considered in turn.
+///
+/// Matches at least `max_lazy_length` long are taken immediately, and once a
+/// match at least `good_length` long is found, the next position is only
+/// searched a quarter as thoroughly.
+pub fn lazy_parse<M>(
+    match_finder: &mut M,
+    data: &[u8],
+    start: usize,
+    good_length: usize,
+    max_lazy_length: usize,
+) -> Vec<Symbol>
+where
+    M: MatchFinder,
+{
+    insert_history(match_finder, data, start);
+
+    let mut symbols = vec![];
+    let mut pos = start;
+
+    // A match starting at `pos - 1`, held back in case `pos` has a longer one
+    let mut prev_match: Option<Match> = None;
+
+    while pos < data.len() {
+        let prev_length = prev_match.map_or(0, |m| m.length);
+        let match_ = if prev_length < max_lazy_length {
+            let max_chain_length = if prev_length >= good_length {
+                match_finder.max_chain_length() / 4
+            } else {
+                match_finder.max_chain_length()
+            };
+            match_finder.longest_match_within_chain(data, pos, data.len(), max_chain_length)
+        } else {
+            None
+        };
+
+        match prev_match {
+            Some(prev) if match_.is_none_or(|m| m.length <= prev.length) => {
+                symbols.push(prev.into());
+                for insert_pos in pos..pos - 1 + prev.length {
+                    match_finder.insert(data, insert_pos);
+                }
+                pos += prev.length - 1;
+                prev_match = None;
+            }
+            Some(_) => {
+                symbols.push(Symbol::Literal(data[pos - 1]));
+                match_finder.insert(data, pos);
+                prev_match = match_;
+                pos += 1;
+            }
+            None => {
+                if match_.is_none() {
+                    symbols.push(Symbol::Literal(data[pos]));
+                }
+                match_finder.insert(data, pos);
+                prev_match = match_;
+                pos += 1;
+            }
+        }
+    }
+
+    symbols
+}
+
+/// The cost in bits of each literal/length code and each distance code, not
+/// counting extra bits.
+#[derive(Clone, Debug)]
+pub struct SymbolCosts {
+    pub length_code_costs: Vec<u32>,
+    pub distance_code_costs: Vec<u32>,
+}
+
+impl SymbolCosts {
+    pub fn symbol_cost(&self, symbol: Symbol) -> u32 {
+        let mut cost = self.length_code_costs[usize::from(symbol.length_code())]
+            + u32::from(symbol.extra_bit_count());
+
+        if let Symbol::BackReference {
+            distance_minus_one, ..
+        } = symbol
+        {
+            let distance_code = Symbol::back_reference_distance_code(distance_minus_one);
+            cost += self.distance_code_costs[usize::from(distance_code)];
+        }
+
+        cost
+    }
+}
+
+/// Finds the matches available at every position of `data[start..]`, for
+/// use by `optimal_parse`.
+pub fn find_all_matches<M>(match_finder: &mut M, data: &[u8], start: usize) -> Vec<Vec<Match>>
+where
+    M: MatchFinder,
+{
+    insert_history(match_finder, data, start);
+
+    (start..data.len())
+        .map(|pos| {
+            let matches = match_finder.matches(data, pos, data.len());
+            match_finder.insert(data, pos);
+            matches
+        })
+        .collect()
+}
+
+/// Parses `data[start..]` into the sequence of literals and back-references
+/// with the lowest total cost, choosing from the matches found by
+/// `find_all_matches`.
+pub fn optimal_parse(
+    data: &[u8],
+    start: usize,
+    matches: &[Vec<Match>],
+    costs: &SymbolCosts,
+) -> Vec<Symbol> {
+    let len = data.len() - start;
+
+    let length_costs = (MIN_MATCH_LENGTH..=MAX_MATCH_LENGTH)
+        .map(|length| {
+            let length_minus_three = (length - MIN_MATCH_LENGTH).try_into().unwrap();
+            costs.length_code_costs
+                [usize::from(Symbol::back_reference_length_code(length_minus_three))]
+                + u32::from(Symbol::back_reference_length_extra_bits(length_minus_three))
+        })
+        .collect::<Vec<_>>();
+
+    // The cheapest cost of reaching each offset, and the last symbol on the
+    // way there
+    let mut best_costs = vec![u64::MAX; len + 1];
+    let mut best_symbols = vec![Symbol::EndOfBlock; len + 1];
+    best_costs[0] = 0;
+
+    for offset in 0..len {
+        let cost = best_costs[offset];
+
+        let literal = Symbol::Literal(data[start + offset]);
+        let literal_cost = cost + u64::from(costs.symbol_cost(literal));
+        if literal_cost < best_costs[offset + 1] {
+            best_costs[offset + 1] = literal_cost;
+            best_symbols[offset + 1] = literal;
+        }
+
+        // Each match covers the lengths between the previous match's length
+        // and its own
+        let mut min_length = MIN_MATCH_LENGTH;
+        for &Match { length, distance } in &matches[offset] {
+            let distance_minus_one = (distance - 1).try_into().unwrap();
+            let distance_code = Symbol::back_reference_distance_code(distance_minus_one);
+            let distance_cost = costs.distance_code_costs[usize::from(distance_code)]
+                + u32::from(Symbol::back_reference_distance_extra_bits(
+                    distance_minus_one,
+                ));
+
+            for length in min_length..=length {
+                let match_cost =
+                    cost + u64::from(length_costs[length - MIN_MATCH_LENGTH] + distance_cost);
+                if match_cost < best_costs[offset + length] {
+                    best_costs[offset + length] = match_cost;
+                    best_symbols[offset + length] = Match { length, distance }.into();
+                }
+            }
+            min_length = length + 1;
+        }
+    }
+
+    let mut symbols = vec![];
+    let mut offset = len;
+    while offset > 0 {
+        let symbol = best_symbols[offset];
+        offset -= symbol.decoded_len();
+        symbols.push(symbol);
+    }
+
+    symbols.reverse();
+    symbols
 }
 
 #[derive(Debug, Default)]
@@ -89,6 +804,7 @@ impl OutBuffer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::xorshift;
     use std::collections::HashMap;
 
     fn expected_lengths_by_code() -> HashMap<u16, Vec<u16>> {
@@ -164,6 +880,296 @@ mod tests {
         .collect()
     }
 
+    /// Expands symbols back into bytes, checking that every back-reference is
+    /// valid.
+    fn expand(history: &[u8], symbols: &[Symbol]) -> Vec<u8> {
+        let mut out = history.to_vec();
+        for &symbol in symbols {
+            match symbol {
+                Symbol::Literal(byte) => out.push(byte),
+                Symbol::EndOfBlock => {}
+                Symbol::BackReference {
+                    length_minus_three,
+                    distance_minus_one,
+                } => {
+                    let distance = usize::from(distance_minus_one) + 1;
+                    assert!(distance <= out.len());
+                    for _ in 0..usize::from(length_minus_three) + MIN_MATCH_LENGTH {
+                        out.push(out[out.len() - distance]);
+                    }
+                }
+            }
+        }
+
+        out.split_off(history.len())
+    }
+
+    fn pseudorandom_text(len: usize) -> Vec<u8> {
+        let words: [&[u8]; 8] = [
+            b"deflate ",
+            b"huffman ",
+            b"window ",
+            b"symbol ",
+            b"block ",
+            b"the ",
+            b"a ",
+            b"\n",
+        ];
+
+        let mut text = vec![];
+        let mut state = 0x2545_f491u32;
+        while text.len() < len {
+            text.extend_from_slice(words[(xorshift(&mut state) % 8) as usize]);
+        }
+
+        text.truncate(len);
+        text
+    }
+
+    #[test]
+    fn test_greedy_parse() {
+        let symbols = greedy_parse(
+            &mut HashChain::new(15, MAX_DISTANCE_BYTES, MIN_MATCH_LENGTH, 128, 258),
+            b"abcabcabcx",
+            0,
+            258,
+        );
+        assert_eq!(
+            symbols,
+            [
+                Symbol::Literal(b'a'),
+                Symbol::Literal(b'b'),
+                Symbol::Literal(b'c'),
+                Symbol::BackReference {
+                    length_minus_three: 3,
+                    distance_minus_one: 2,
+                },
+                Symbol::Literal(b'x'),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_greedy_parse_round_trip() {
+        let data = pseudorandom_text(100_000);
+        let (history, rest) = data.split_at(40_000);
+
+        let symbols = greedy_parse(
+            &mut HashChain::new(15, MAX_DISTANCE_BYTES, MIN_MATCH_LENGTH, 128, 258),
+            &data,
+            history.len(),
+            258,
+        );
+        assert!(symbols.len() < rest.len() / 2);
+        assert_eq!(expand(history, &symbols), rest);
+    }
+
+    #[test]
+    fn test_greedy_parse_long_runs() {
+        let data = [vec![0u8; 1000], vec![1; 1], vec![0; 1000]].concat();
+
+        let symbols = greedy_parse(
+            &mut HashChain::new(15, MAX_DISTANCE_BYTES, MIN_MATCH_LENGTH, 128, 258),
+            &data,
+            0,
+            258,
+        );
+        assert_eq!(expand(&[], &symbols), data);
+    }
+
+    #[test]
+    fn test_lazy_parse_prefers_longer_match() {
+        // At the second "bcdef", greedy parsing takes "bcd" (distance 10),
+        // while lazy parsing emits "b" and then takes "cdef" (distance 6).
+        let data = b"bcdxxcdefxbcdef";
+        let greedy = greedy_parse(
+            &mut HashChain::new(15, MAX_DISTANCE_BYTES, MIN_MATCH_LENGTH, 128, 258),
+            data,
+            0,
+            258,
+        );
+        let lazy = lazy_parse(
+            &mut HashChain::new(15, MAX_DISTANCE_BYTES, MIN_MATCH_LENGTH, 128, 258),
+            data,
+            0,
+            258,
+            258,
+        );
+
+        assert_eq!(greedy.len(), 13);
+        assert_eq!(
+            lazy[10..],
+            [
+                Symbol::Literal(b'b'),
+                Match {
+                    length: 4,
+                    distance: 6,
+                }
+                .into(),
+            ]
+        );
+        assert_eq!(expand(&[], &greedy), data);
+        assert_eq!(expand(&[], &lazy), data);
+    }
+
+    #[test]
+    fn test_lazy_parse_round_trip() {
+        let data = pseudorandom_text(100_000);
+        let (history, rest) = data.split_at(40_000);
+
+        for (good_length, max_lazy_length) in [(4, 4), (8, 16), (32, 258)] {
+            let symbols = lazy_parse(
+                &mut HashChain::new(15, MAX_DISTANCE_BYTES, MIN_MATCH_LENGTH, 128, 258),
+                &data,
+                history.len(),
+                good_length,
+                max_lazy_length,
+            );
+            assert_eq!(expand(history, &symbols), rest);
+        }
+    }
+
+    #[test]
+    fn test_min_match_length() {
+        let data = pseudorandom_text(20_000);
+        let symbols = greedy_parse(
+            &mut HashChain::new(15, MAX_DISTANCE_BYTES, 6, 128, 258),
+            &data,
+            0,
+            258,
+        );
+
+        assert_eq!(expand(&[], &symbols), data);
+        assert!(symbols.iter().all(|symbol| match symbol {
+            Symbol::BackReference {
+                length_minus_three, ..
+            } => *length_minus_three >= 3,
+            _ => true,
+        }));
+    }
+
+    #[test]
+    fn test_window_size() {
+        let data = pseudorandom_text(20_000);
+        let mut match_finder = HashChain::new(9, 512, MIN_MATCH_LENGTH, 128, 258);
+        let symbols = greedy_parse(&mut match_finder, &data, 0, 258);
+
+        assert_eq!(expand(&[], &symbols), data);
+        assert!(symbols.iter().all(|symbol| match symbol {
+            Symbol::BackReference {
+                distance_minus_one, ..
+            } => *distance_minus_one < 512,
+            _ => true,
+        }));
+    }
+
+    #[test]
+    fn test_rle_parse() {
+        let data = [b"ab".as_slice(), &[b'c'; 300], b"dd", &[b'e'; 4]].concat();
+        let (history, rest) = data.split_at(3);
+
+        assert_eq!(
+            rle_parse(&data, history.len()),
+            [
+                Match {
+                    length: 258,
+                    distance: 1,
+                }
+                .into(),
+                Match {
+                    length: 41,
+                    distance: 1,
+                }
+                .into(),
+                Symbol::Literal(b'd'),
+                Symbol::Literal(b'd'),
+                Symbol::Literal(b'e'),
+                Match {
+                    length: 3,
+                    distance: 1,
+                }
+                .into(),
+            ]
+        );
+        assert_eq!(expand(history, &rle_parse(&data, history.len())), rest);
+    }
+
+    #[test]
+    fn test_optimal_parse_is_cheapest() {
+        let data = pseudorandom_text(20_000);
+        let (history, rest) = data.split_at(5_000);
+
+        // Roughly what a Huffman code would assign to English-like text
+        let costs = SymbolCosts {
+            length_code_costs: (0..286)
+                .map(|code| if code < 256 { 6 } else { 8 })
+                .collect(),
+            distance_code_costs: vec![5; 30],
+        };
+        let total_cost = |symbols: &[Symbol]| -> u32 {
+            symbols
+                .iter()
+                .map(|&symbol| costs.symbol_cost(symbol))
+                .sum()
+        };
+
+        let mut match_finder = HashChain::new(15, MAX_DISTANCE_BYTES, MIN_MATCH_LENGTH, 128, 258);
+        let matches = find_all_matches(&mut match_finder, &data, history.len());
+        let optimal = optimal_parse(&data, history.len(), &matches, &costs);
+        let lazy = lazy_parse(&mut match_finder, &data, history.len(), 258, 258);
+
+        assert_eq!(expand(history, &optimal), rest);
+        assert!(total_cost(&optimal) <= total_cost(&lazy));
+    }
+
+    #[test]
+    fn test_binary_tree_finds_longest_matches() {
+        let data = [
+            pseudorandom_text(2000),
+            vec![b'z'; 300],
+            pseudorandom_text(1000),
+        ]
+        .concat();
+        let mut match_finder = BinaryTree::new(15, 1024, MIN_MATCH_LENGTH, usize::MAX, 258);
+
+        for pos in 0..data.len() {
+            let expected_length = (1..=pos.min(1024))
+                .map(|distance| {
+                    data[pos - distance..]
+                        .iter()
+                        .zip(&data[pos..])
+                        .take(MAX_MATCH_LENGTH)
+                        .take_while(|(a, b)| a == b)
+                        .count()
+                })
+                .max()
+                .filter(|&length| length >= MIN_MATCH_LENGTH);
+
+            let match_ = match_finder.longest_match(&data, pos, data.len());
+            assert_eq!(match_.map(|m| m.length), expected_length, "at {pos}");
+            if let Some(Match { length, distance }) = match_ {
+                assert_eq!(data[pos - distance..][..length], data[pos..][..length]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_binary_tree_round_trip() {
+        let data = pseudorandom_text(50_000);
+        let (history, rest) = data.split_at(20_000);
+        let mut match_finder = BinaryTree::new(15, MAX_DISTANCE_BYTES, MIN_MATCH_LENGTH, 128, 32);
+
+        let symbols = greedy_parse(&mut match_finder, &data, history.len(), 258);
+        assert_eq!(expand(history, &symbols), rest);
+
+        let symbols = lazy_parse(&mut match_finder, &data, history.len(), 8, 32);
+        assert_eq!(expand(history, &symbols), rest);
+
+        let data = [vec![0u8; 1000], vec![1; 1], vec![0; 1000]].concat();
+        let symbols = greedy_parse(&mut match_finder, &data, 0, 258);
+        assert_eq!(expand(&[], &symbols), data);
+    }
+
     #[test]
     fn test_back_reference_length_codes() {
         let mut actual_lengths_by_code = <HashMap<u16, Vec<u16>>>::new();
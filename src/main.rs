@@ -1,12 +1,85 @@
-use clap::{Parser, Subcommand};
-use deflate_rs::{BitReader, DeflateDecoder, DeflateEncoder, GzipDecoder};
-use std::io;
+use clap::{Parser, Subcommand, ValueEnum};
+use deflate_rs::{
+    BitReader, CompressionLevel, DeflateDecoder, DeflateEncoder, EncoderOptions, GzipDecoder,
+    GzipEncoder, ParallelDeflateEncoder, ParallelGzipEncoder, Strategy, ZlibDecoder, ZlibEncoder,
+};
+use std::{io, num::NonZeroUsize};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum StrategyArg {
+    Default,
+    Filtered,
+    HuffmanOnly,
+    Rle,
+}
+
+impl From<StrategyArg> for Strategy {
+    fn from(strategy: StrategyArg) -> Self {
+        match strategy {
+            StrategyArg::Default => Self::Default,
+            StrategyArg::Filtered => Self::Filtered,
+            StrategyArg::HuffmanOnly => Self::HuffmanOnly,
+            StrategyArg::Rle => Self::Rle,
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct EncodeArgs {
+    /// Compression level, from 0 (stored only) to 9 (best compression)
+    #[arg(short, long, default_value_t = 6, value_parser = clap::value_parser!(u8).range(0..=9))]
+    level: u8,
+
+    /// Which matches to look for
+    #[arg(short, long, value_enum, default_value_t = StrategyArg::Default)]
+    strategy: StrategyArg,
+
+    /// Base-2 logarithm of the window size
+    #[arg(short, long, default_value_t = 15, value_parser = clap::value_parser!(u8).range(9..=15))]
+    window_bits: u8,
+
+    /// How much memory to use for finding matches
+    #[arg(short, long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(1..=9))]
+    mem_level: u8,
+
+    /// Decode the output while compressing, and fail if it doesn't match the
+    /// input
+    #[arg(long)]
+    verify: bool,
+
+    /// Flush at points picked from the input, so that a local change to the
+    /// input only changes the output nearby
+    #[arg(long)]
+    rsyncable: bool,
+
+    /// Compress chunks of the input on this many threads, like pigz. The
+    /// output is the same for any number of threads, but slightly larger than
+    /// without this option
+    #[arg(short, long)]
+    threads: Option<NonZeroUsize>,
+}
+
+impl EncodeArgs {
+    fn options(&self) -> anyhow::Result<EncoderOptions> {
+        Ok(EncoderOptions {
+            strategy: self.strategy.into(),
+            window_bits: self.window_bits.try_into()?,
+            mem_level: self.mem_level.try_into()?,
+            verify: self.verify,
+            rsyncable: self.rsyncable,
+            ..CompressionLevel::try_from(self.level)?.into()
+        })
+    }
+}
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    DeflateEncode,
+    DeflateEncode(EncodeArgs),
     DeflateDecode,
+    GzipEncode(EncodeArgs),
     GzipDecode,
+    ZlibEncode(EncodeArgs),
+    ZlibDecode,
 }
 
 #[derive(Debug, Parser)]
@@ -18,9 +91,14 @@ struct Args {
 fn main() -> anyhow::Result<()> {
     let Args { command } = Args::try_parse()?;
     match command {
-        Command::DeflateEncode => {
-            let mut encoder = DeflateEncoder::new();
-            encoder.encode(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+        Command::DeflateEncode(args) => {
+            if let Some(threads) = args.threads {
+                let encoder = ParallelDeflateEncoder::new(args.options()?, threads.get());
+                encoder.encode(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+            } else {
+                let mut encoder = DeflateEncoder::with_options(args.options()?);
+                encoder.encode(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+            }
             Ok(())
         }
         Command::DeflateDecode => {
@@ -31,6 +109,16 @@ fn main() -> anyhow::Result<()> {
             )?;
             Ok(())
         }
+        Command::GzipEncode(args) => {
+            if let Some(threads) = args.threads {
+                let encoder = ParallelGzipEncoder::new(args.options()?, threads.get());
+                encoder.encode(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+            } else {
+                let mut encoder = GzipEncoder::with_options(args.options()?);
+                encoder.encode(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+            }
+            Ok(())
+        }
         Command::GzipDecode => {
             let mut decoder = GzipDecoder::new();
             decoder.decode(
@@ -39,5 +127,22 @@ fn main() -> anyhow::Result<()> {
             )?;
             Ok(())
         }
+        Command::ZlibEncode(args) => {
+            if args.threads.is_some() {
+                anyhow::bail!("--threads is only supported for deflate and gzip");
+            }
+
+            let mut encoder = ZlibEncoder::with_options(args.options()?);
+            encoder.encode(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+            Ok(())
+        }
+        Command::ZlibDecode => {
+            let mut decoder = ZlibDecoder::new();
+            decoder.decode(
+                &mut BitReader::new(&mut io::stdin().lock()),
+                &mut io::stdout().lock(),
+            )?;
+            Ok(())
+        }
     }
 }
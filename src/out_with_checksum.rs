@@ -41,3 +41,50 @@ where
         self.out.flush()
     }
 }
+
+/// Like `OutWithChecksum`, but for the bytes read from a reader.
+#[derive(Debug)]
+pub struct InWithChecksum<I> {
+    in_: I,
+    size: u32,
+    crc_hasher: crc32fast::Hasher,
+}
+
+impl<I> InWithChecksum<I> {
+    pub fn new(in_: I) -> Self {
+        Self {
+            in_,
+            size: 0,
+            crc_hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &I {
+        &self.in_
+    }
+
+    pub fn into_inner(self) -> I {
+        self.in_
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn crc32(&self) -> u32 {
+        self.crc_hasher.clone().finalize()
+    }
+}
+
+impl<I> io::Read for InWithChecksum<I>
+where
+    I: io::Read,
+{
+    #[allow(clippy::cast_possible_truncation)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.in_.read(buf)?;
+        self.crc_hasher.update(&buf[..bytes]);
+        self.size = self.size.wrapping_add(bytes as u32);
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,312 @@
+use crate::{
+    deflate::{self, EncoderOptions, RsyncHash},
+    verify::{VerifiedOut, Verifier},
+};
+use std::{
+    io::{self, Read, Write},
+    thread,
+};
+
+/// The default size of the chunks that are compressed on separate threads.
+/// This is what pigz uses.
+pub const DEFAULT_CHUNK_LEN: usize = 128 * 1024;
+
+/// Encodes data as a Deflate stream on several threads, like pigz.
+///
+/// The input is split into chunks, and each chunk is compressed separately,
+/// with the end of the input before it as a preset dictionary. The chunks are
+/// joined with sync flushes, which costs a few bytes each. The output only
+/// depends on the options and the chunk size, so it is the same for any
+/// number of threads.
+///
+/// With `EncoderOptions::rsyncable`, chunks end at the last rsync point in
+/// them instead, like pigz does, and chunks after a point don't refer back
+/// to the input before it. That way a change to the input only changes the
+/// output up to the next point, as with a single thread.
+///
+/// At most one chunk per thread is held in memory at a time, along with its
+/// output.
+#[derive(Clone, Copy, Debug)]
+pub struct ParallelDeflateEncoder {
+    options: EncoderOptions,
+    threads: usize,
+    chunk_len: usize,
+}
+
+impl ParallelDeflateEncoder {
+    pub fn new(options: EncoderOptions, threads: usize) -> Self {
+        Self::with_chunk_len(options, threads, DEFAULT_CHUNK_LEN)
+    }
+
+    /// Creates an encoder that splits the input into chunks of `chunk_len`
+    /// bytes. Smaller chunks compress worse, and larger chunks need more
+    /// memory.
+    pub fn with_chunk_len(options: EncoderOptions, threads: usize, chunk_len: usize) -> Self {
+        assert!(threads > 0, "at least one thread is needed");
+        assert!(chunk_len > 0, "chunks can't be empty");
+
+        Self {
+            options,
+            threads,
+            chunk_len,
+        }
+    }
+
+    pub fn encode<R, W>(&self, in_: &mut R, out: &mut W) -> io::Result<()>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        self.encode_with_checksum(in_, out)?;
+        Ok(())
+    }
+
+    /// Like `encode`, but also returns the CRC-32 and the size of the input,
+    /// modulo 2^32, as gzip records them. The CRC-32 of each chunk is found
+    /// on the thread that compresses it.
+    pub fn encode_with_checksum<R, W>(&self, in_: &mut R, out: &mut W) -> io::Result<(u32, u32)>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        // Each chunk is checked by one decoder for the whole stream instead
+        let chunk_options = EncoderOptions {
+            verify: false,
+            ..self.options
+        };
+        let mut verifier = self.options.verify.then(|| Verifier::new(&[]));
+        let mut out = VerifiedOut::new(&mut *out, verifier.as_ref().and_then(Verifier::sender));
+
+        let window_size = self.options.window_bits.window_size();
+        let mut history = vec![];
+        let mut crc_hasher = crc32fast::Hasher::new();
+        let mut size: u32 = 0;
+
+        // Reading a chunk ahead shows whether the current chunk is the last
+        let mut chunker = Chunker {
+            in_,
+            chunk_len: self.chunk_len,
+            rsync_hash: self.options.rsyncable.then(RsyncHash::default),
+            buffer: vec![],
+            is_at_rsync_point: false,
+        };
+        let mut next_chunk = chunker.next_chunk()?;
+        let mut is_last = false;
+
+        while !is_last {
+            let mut batch = vec![];
+            while batch.len() < self.threads && !is_last {
+                let (chunk, follows_rsync_point) = next_chunk;
+                next_chunk = chunker.next_chunk()?;
+                is_last = next_chunk.0.is_empty();
+
+                // The encoder resets its window at each rsync point
+                if follows_rsync_point {
+                    history.clear();
+                }
+                let dictionary = history.clone();
+                history.extend_from_slice(&chunk);
+                history.drain(..history.len().saturating_sub(window_size));
+                batch.push((dictionary, chunk, is_last));
+            }
+
+            let results = thread::scope(|scope| {
+                let handles: Vec<_> = batch
+                    .iter()
+                    .map(|(dictionary, chunk, is_last)| {
+                        scope.spawn(move || {
+                            let mut chunk_crc_hasher = crc32fast::Hasher::new();
+                            chunk_crc_hasher.update(chunk);
+                            let compressed =
+                                deflate::encode_chunk(chunk_options, dictionary, chunk, *is_last)?;
+                            Ok((compressed, chunk_crc_hasher))
+                        })
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle.join().unwrap_or_else(|_| {
+                            Err(io::Error::other("compression thread panicked"))
+                        })
+                    })
+                    .collect::<io::Result<Vec<_>>>()
+            })?;
+
+            for ((_, chunk, _), (compressed, chunk_crc_hasher)) in batch.iter().zip(results) {
+                out.write_all(&compressed)?;
+                crc_hasher.combine(&chunk_crc_hasher);
+                size = size.wrapping_add(chunk.len() as u32);
+                if let Some(verifier) = &mut verifier {
+                    verifier.write_input(chunk);
+                }
+            }
+        }
+
+        out.flush()?;
+        drop(out);
+        if let Some(verifier) = verifier {
+            verifier.finish()?;
+        }
+
+        Ok((crc_hasher.finalize(), size))
+    }
+}
+
+/// Splits the input into the chunks that `ParallelDeflateEncoder` compresses.
+struct Chunker<'a, R> {
+    in_: &'a mut R,
+    chunk_len: usize,
+    /// The rolling hash of the input so far, with `rsyncable`
+    rsync_hash: Option<RsyncHash>,
+    /// Input that has been read, but isn't in a chunk yet
+    buffer: Vec<u8>,
+    /// Whether the last chunk ended at an rsync point
+    is_at_rsync_point: bool,
+}
+
+impl<R> Chunker<'_, R>
+where
+    R: io::Read,
+{
+    /// Returns the next chunk, and whether it starts right after an rsync
+    /// point. Chunks are `chunk_len` bytes long, except at the end of the
+    /// input, where the chunk is empty, or when they end at an rsync point.
+    fn next_chunk(&mut self) -> io::Result<(Vec<u8>, bool)> {
+        // What is still buffered has been hashed already
+        let hashed_len = self.buffer.len();
+        let missing_len = self.chunk_len - self.buffer.len();
+        (&mut *self.in_)
+            .take(missing_len as u64)
+            .read_to_end(&mut self.buffer)?;
+
+        let follows_rsync_point = self.is_at_rsync_point;
+        self.is_at_rsync_point = false;
+
+        let mut end = self.buffer.len();
+        if let Some(rsync_hash) = &mut self.rsync_hash {
+            for (pos, &byte) in self.buffer.iter().enumerate().skip(hashed_len) {
+                if rsync_hash.update(byte) {
+                    end = pos + 1;
+                    self.is_at_rsync_point = true;
+                }
+            }
+        }
+
+        let chunk = self.buffer.drain(..end).collect();
+        Ok((chunk, follows_rsync_point))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bit_io::BitReader,
+        deflate::DeflateDecoder,
+        test_utils::{pseudorandom_bytes, sample_text},
+        CompressionLevel,
+    };
+
+    fn decode(compressed: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        DeflateDecoder::new()
+            .decode(&mut BitReader::new(&mut &*compressed), &mut out)
+            .unwrap();
+        out
+    }
+
+    fn encode(data: &[u8], options: EncoderOptions, threads: usize, chunk_len: usize) -> Vec<u8> {
+        let mut out = vec![];
+        ParallelDeflateEncoder::with_chunk_len(options, threads, chunk_len)
+            .encode(&mut &*data, &mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_same_output_for_any_thread_count() {
+        let data = sample_text().repeat(5);
+
+        for level in [CompressionLevel::NONE, CompressionLevel::DEFAULT] {
+            let compressed = encode(&data, level.into(), 1, 10_000);
+            assert_eq!(decode(&compressed), data);
+
+            for threads in [2, 3, 8] {
+                assert_eq!(encode(&data, level.into(), threads, 10_000), compressed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunk_boundaries() {
+        let data = sample_text();
+
+        // Empty input, one chunk, and input that ends on a chunk boundary
+        for (len, chunk_len) in [(0, 1000), (500, 1000), (3000, 1000), (3000, 5000)] {
+            let compressed = encode(&data[..len], EncoderOptions::default(), 2, chunk_len);
+            assert_eq!(decode(&compressed), data[..len]);
+        }
+    }
+
+    #[test]
+    fn test_chunks_use_dictionary() {
+        // Only compressible by referring back into the previous chunk
+        let random = pseudorandom_bytes(2000);
+        let data = random.repeat(4);
+
+        let compressed = encode(&data, EncoderOptions::default(), 4, random.len());
+        assert_eq!(decode(&compressed), data);
+        assert!(compressed.len() < 2 * random.len());
+    }
+
+    #[test]
+    fn test_checksum_and_verify() {
+        let data = sample_text().repeat(5);
+        let options = EncoderOptions {
+            verify: true,
+            ..EncoderOptions::default()
+        };
+
+        let mut compressed = vec![];
+        let (crc32, size) = ParallelDeflateEncoder::with_chunk_len(options, 4, 7000)
+            .encode_with_checksum(&mut &*data, &mut compressed)
+            .unwrap();
+        assert_eq!(crc32, crc32fast::hash(&data));
+        assert_eq!(size as usize, data.len());
+        assert_eq!(decode(&compressed), data);
+    }
+
+    #[test]
+    fn test_rsyncable() {
+        let options = EncoderOptions {
+            rsyncable: true,
+            ..EncoderOptions::default()
+        };
+
+        let data: Vec<u8> = pseudorandom_bytes(200_000)
+            .into_iter()
+            .map(|byte| b'a' + byte % 16)
+            .collect();
+
+        let compressed = encode(&data, options, 4, 20_000);
+        assert_eq!(decode(&compressed), data);
+        assert_eq!(encode(&data, options, 1, 20_000), compressed);
+
+        // After an inserted byte, the output is the same again from the next
+        // rsync point, even though the chunks after it start elsewhere
+        let mut edited = data.clone();
+        edited.insert(10_000, b'!');
+        let edited_compressed = encode(&edited, options, 4, 20_000);
+        assert_eq!(decode(&edited_compressed), edited);
+
+        let common_suffix_len = compressed
+            .iter()
+            .rev()
+            .zip(edited_compressed.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        assert!(common_suffix_len > compressed.len() * 3 / 4);
+    }
+}
@@ -0,0 +1,26 @@
+//! Input data for the tests of several modules.
+
+/// Advances a xorshift generator, and returns its new state. `state` must not
+/// be 0.
+pub fn xorshift(state: &mut u32) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+/// Bytes that hardly compress at all, the same for each `len`.
+pub fn pseudorandom_bytes(len: usize) -> Vec<u8> {
+    let mut state = 0x1234_5678u32;
+    (0..len)
+        .map(|_| xorshift(&mut state).to_le_bytes()[0])
+        .collect()
+}
+
+/// Repetitive text, which compresses well.
+pub fn sample_text() -> Vec<u8> {
+    b"It was the best of times, it was the worst of times, it was the age of wisdom, \
+      it was the age of foolishness, it was the epoch of belief, it was the epoch of \
+      incredulity, it was the season of Light, it was the season of Darkness.\n"
+        .repeat(40)
+}
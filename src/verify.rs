@@ -0,0 +1,281 @@
+use crate::{bit_io::BitReader, deflate::DeflateDecoder, out_with_checksum::OutWithChecksum};
+use std::{
+    io, mem,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread::{self, JoinHandle},
+};
+
+/// How many chunks of compressed data can wait for the decoder before the
+/// encoder blocks.
+const MAX_PENDING_CHUNKS: usize = 16;
+
+/// How many bytes of compressed data `VerifiedOut` collects before sending
+/// them to the decoder.
+const SEND_CHUNK_LEN: usize = 64 * 1024;
+
+/// Checks that a Deflate stream decodes back to its input, while both are
+/// being produced. The stream is decoded on another thread as it is written,
+/// and the CRC-32 of its output is compared to that of the input, so neither
+/// has to be held in memory.
+#[derive(Debug)]
+pub struct Verifier {
+    sender: Option<SyncSender<Vec<u8>>>,
+    decoder: JoinHandle<io::Result<(u32, u32)>>,
+    input_size: u32,
+    input_crc_hasher: crc32fast::Hasher,
+}
+
+impl Verifier {
+    /// Starts decoding, with the same preset dictionary as the encoder.
+    pub fn new(dictionary: &[u8]) -> Self {
+        let dictionary = dictionary.to_vec();
+        let (sender, receiver) = mpsc::sync_channel(MAX_PENDING_CHUNKS);
+
+        let decoder = thread::spawn(move || {
+            let mut decoder = DeflateDecoder::with_dictionary(&dictionary)?;
+            let mut in_ = ChannelReader {
+                receiver,
+                chunk: vec![],
+                pos: 0,
+            };
+            let mut sink = io::sink();
+            let mut out_with_checksum = OutWithChecksum::new(&mut sink);
+            decoder.decode(&mut BitReader::new(&mut in_), &mut out_with_checksum)?;
+
+            Ok((out_with_checksum.crc32(), out_with_checksum.size()))
+        });
+
+        Self {
+            sender: Some(sender),
+            decoder,
+            input_size: 0,
+            input_crc_hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    /// Records bytes of input that have been encoded.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn write_input(&mut self, bytes: &[u8]) {
+        self.input_crc_hasher.update(bytes);
+        self.input_size = self.input_size.wrapping_add(bytes.len() as u32);
+    }
+
+    /// Returns a sender for the compressed stream, to be passed to
+    /// `VerifiedOut`.
+    pub fn sender(&self) -> Option<SyncSender<Vec<u8>>> {
+        self.sender.clone()
+    }
+
+    /// Waits for the whole stream to be decoded, and checks that it matches
+    /// the input. Every sender must have been dropped first.
+    pub fn finish(mut self) -> io::Result<()> {
+        drop(self.sender.take());
+
+        let (output_crc32, output_size) = self
+            .decoder
+            .join()
+            .map_err(|_| io::Error::other("verifying decoder panicked"))??;
+        let input_crc32 = self.input_crc_hasher.finalize();
+
+        if output_crc32 != input_crc32 || output_size != self.input_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "compressed data does not decode to its input; got CRC-32 {output_crc32:#010x} and size {output_size}, expected {input_crc32:#010x} and size {}",
+                    self.input_size
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the chunks sent to a channel, until every sender is dropped.
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl io::Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Sends compressed data to a `Verifier`'s decoder in chunks of
+/// `SEND_CHUNK_LEN` bytes, since `BitWriter` writes a byte at a time. The
+/// rest is sent when it is dropped.
+#[derive(Debug)]
+struct ChunkSender {
+    sender: SyncSender<Vec<u8>>,
+    chunk: Vec<u8>,
+}
+
+impl ChunkSender {
+    fn new(sender: SyncSender<Vec<u8>>) -> Self {
+        Self {
+            sender,
+            chunk: Vec::with_capacity(SEND_CHUNK_LEN),
+        }
+    }
+
+    /// Returns `false` if the decoder has stopped.
+    fn write(&mut self, buf: &[u8]) -> bool {
+        self.chunk.extend_from_slice(buf);
+        self.chunk.len() < SEND_CHUNK_LEN || self.send()
+    }
+
+    /// Sends the current chunk. Returns `false` if the decoder has stopped.
+    fn send(&mut self) -> bool {
+        if self.chunk.is_empty() {
+            return true;
+        }
+
+        let chunk = mem::replace(&mut self.chunk, Vec::with_capacity(SEND_CHUNK_LEN));
+        self.sender.send(chunk).is_ok()
+    }
+}
+
+impl Drop for ChunkSender {
+    fn drop(&mut self) {
+        // If the decoder stopped early, it returns the error from `finish`
+        self.send();
+    }
+}
+
+/// Writes to an inner writer, and sends what was written to a `Verifier`'s
+/// decoder if there is one.
+#[derive(Debug)]
+pub struct VerifiedOut<W> {
+    inner: W,
+    sender: Option<ChunkSender>,
+}
+
+impl<W> VerifiedOut<W> {
+    pub fn new(inner: W, sender: Option<SyncSender<Vec<u8>>>) -> Self {
+        Self {
+            inner,
+            sender: sender.map(ChunkSender::new),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns the inner writer, after sending the rest of the output to the
+    /// decoder.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Sends the rest of the output to the decoder, and stops sending, so that
+    /// the `Verifier` can finish.
+    pub fn stop_sending(&mut self) {
+        self.sender = None;
+    }
+}
+
+impl<W> io::Write for VerifiedOut<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes = self.inner.write(buf)?;
+
+        // If the decoder stopped early, it returns the error from `finish`
+        if let Some(sender) = &mut self.sender {
+            if !sender.write(&buf[..bytes]) {
+                self.sender = None;
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(sender) = &mut self.sender {
+            if !sender.send() {
+                self.sender = None;
+            }
+        }
+
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompressionLevel, DeflateEncoder};
+    use std::io::Write;
+
+    #[test]
+    fn test_verifier() {
+        // A fixed Huffman block holding "abc"
+        let compressed = [0x4b, 0x4c, 0x4a, 0x06, 0x00];
+
+        for (input, is_ok) in [(&b"abc"[..], true), (b"abd", false), (b"ab", false)] {
+            let mut verifier = Verifier::new(&[]);
+            verifier.write_input(input);
+
+            let mut out = VerifiedOut::new(vec![], verifier.sender());
+            out.write_all(&compressed).unwrap();
+            drop(out);
+
+            assert_eq!(verifier.finish().is_ok(), is_ok);
+        }
+    }
+
+    #[test]
+    fn test_verifier_small_writes() {
+        // Stored blocks, so that the output is longer than a chunk
+        let input = (0..200_000u32)
+            .map(|idx| idx.wrapping_mul(2_654_435_761).to_le_bytes()[3])
+            .collect::<Vec<_>>();
+        let mut compressed = vec![];
+        DeflateEncoder::with_level(CompressionLevel::NONE)
+            .encode(&mut &input[..], &mut compressed)
+            .unwrap();
+
+        let mut verifier = Verifier::new(&[]);
+        verifier.write_input(&input);
+
+        let mut out = VerifiedOut::new(vec![], verifier.sender());
+        for &byte in &compressed {
+            out.write_all(&[byte]).unwrap();
+        }
+        assert_eq!(out.into_inner(), compressed);
+
+        verifier.finish().unwrap();
+    }
+
+    #[test]
+    fn test_verifier_invalid_data() {
+        let verifier = Verifier::new(&[]);
+        let mut out = VerifiedOut::new(vec![], verifier.sender());
+        out.write_all(&[0xff, 0xff]).unwrap();
+        drop(out);
+
+        assert!(verifier.finish().is_err());
+    }
+}
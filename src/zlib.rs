@@ -0,0 +1,542 @@
+use crate::{
+    bit_io::BitReader,
+    deflate::{
+        CompressionLevel, CompressionStats, DeflateDecoder, DeflateEncoder, DeflateReader,
+        EncoderOptions, Strategy,
+    },
+};
+use std::io;
+
+const ZLIB_CM_DEFLATE: u8 = 0x08;
+const ZLIB_MAX_CINFO: u8 = 7;
+const ZLIB_FDICT: u8 = 0x20;
+
+/// Computes the Adler-32 checksum of everything read from or written to the
+/// inner reader or writer.
+#[derive(Debug)]
+struct WithAdler32<T> {
+    inner: T,
+    hasher: adler2::Adler32,
+}
+
+impl<T> WithAdler32<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: adler2::Adler32::new(),
+        }
+    }
+
+    fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn adler32(&self) -> u32 {
+        self.hasher.checksum()
+    }
+}
+
+impl<R> io::Read for WithAdler32<R>
+where
+    R: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.inner.read(buf)?;
+        self.hasher.write_slice(&buf[..bytes]);
+        Ok(bytes)
+    }
+}
+
+impl<W> io::Write for WithAdler32<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes = self.inner.write(buf)?;
+        self.hasher.write_slice(&buf[..bytes]);
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let mut hasher = adler2::Adler32::new();
+    hasher.write_slice(bytes);
+    hasher.checksum()
+}
+
+/// Encodes data in the zlib format (RFC 1950), a Deflate stream with a short
+/// header and an Adler-32 checksum.
+#[derive(Debug)]
+pub struct ZlibEncoder {
+    deflate_encoder: DeflateEncoder,
+    header: [u8; 2],
+    /// The Adler-32 checksum of the preset dictionary, if there is one
+    dictid: Option<u32>,
+}
+
+impl Default for ZlibEncoder {
+    fn default() -> Self {
+        Self::with_options(EncoderOptions::default())
+    }
+}
+
+/// The two header bytes, CMF and FLG. These record the window size, and
+/// roughly how hard the encoder tries, as zlib reports it.
+fn header(options: EncoderOptions, has_dictionary: bool) -> [u8; 2] {
+    let cinfo = options.window_bits.bits() - 8;
+    let cmf = cinfo << 4 | ZLIB_CM_DEFLATE;
+
+    let flevel = match options.strategy {
+        Strategy::HuffmanOnly | Strategy::Rle => 0,
+        Strategy::Default | Strategy::Filtered => match options.level.level() {
+            0..=1 => 0,
+            2..=5 => 1,
+            6 => 2,
+            7.. => 3,
+        },
+    };
+    let mut flg = flevel << 6;
+    if has_dictionary {
+        flg |= ZLIB_FDICT;
+    }
+
+    // FCHECK makes the header a multiple of 31
+    let fcheck = 31 - (u16::from_be_bytes([cmf, flg]) % 31);
+    flg |= u8::try_from(fcheck % 31).unwrap();
+
+    [cmf, flg]
+}
+
+impl ZlibEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_level(level: CompressionLevel) -> Self {
+        Self::with_options(level.into())
+    }
+
+    pub fn with_options(options: EncoderOptions) -> Self {
+        Self {
+            deflate_encoder: DeflateEncoder::with_options(options),
+            header: header(options, false),
+            dictid: None,
+        }
+    }
+
+    /// Creates an encoder that uses a preset dictionary. The header records
+    /// the dictionary's checksum, and the same dictionary must be passed to
+    /// `ZlibDecoder::with_dictionary` to decode.
+    pub fn with_dictionary(options: EncoderOptions, dictionary: &[u8]) -> io::Result<Self> {
+        Ok(Self {
+            deflate_encoder: DeflateEncoder::with_dictionary(options, dictionary)?,
+            header: header(options, true),
+            dictid: Some(adler32(dictionary)),
+        })
+    }
+
+    /// Statistics about the Deflate blocks written so far. See
+    /// `DeflateEncoder::stats`.
+    pub fn stats(&self) -> &CompressionStats {
+        self.deflate_encoder.stats()
+    }
+
+    /// The most bytes `encode` can write for `input_len` bytes of input.
+    /// See `DeflateEncoder::compress_bound`.
+    pub fn compress_bound(&self, input_len: usize) -> usize {
+        let dictid_len = if self.dictid.is_some() { 4 } else { 0 };
+        self.header.len() + dictid_len + self.deflate_encoder.compress_bound(input_len) + 4
+    }
+
+    pub fn encode<R, W>(&mut self, in_: &mut R, out: &mut W) -> io::Result<()>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        out.write_all(&self.header)?;
+        if let Some(dictid) = self.dictid {
+            out.write_all(&dictid.to_be_bytes())?;
+        }
+
+        let mut in_with_adler32 = WithAdler32::new(in_);
+        self.deflate_encoder.encode(&mut in_with_adler32, out)?;
+
+        out.write_all(&in_with_adler32.adler32().to_be_bytes())?;
+        out.flush()
+    }
+}
+
+/// A reader that compresses everything read from an inner reader, and
+/// returns it in the zlib format. See `DeflateReader`.
+#[derive(Debug)]
+pub struct ZlibReader<R> {
+    deflate_reader: DeflateReader<WithAdler32<R>>,
+    /// The header, and then the checksum once the Deflate stream has been read
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+    is_checksum_buffered: bool,
+}
+
+impl<R> ZlibReader<R>
+where
+    R: io::Read,
+{
+    pub fn new(inner: R) -> Self {
+        Self::with_options(inner, EncoderOptions::default())
+    }
+
+    pub fn with_options(inner: R, options: EncoderOptions) -> Self {
+        Self {
+            deflate_reader: DeflateReader::with_options(WithAdler32::new(inner), options),
+            buffer: header(options, false).to_vec(),
+            buffer_pos: 0,
+            is_checksum_buffered: false,
+        }
+    }
+
+    /// Creates a reader that uses a preset dictionary. See
+    /// `ZlibEncoder::with_dictionary`.
+    pub fn with_dictionary(
+        inner: R,
+        options: EncoderOptions,
+        dictionary: &[u8],
+    ) -> io::Result<Self> {
+        let mut buffer = header(options, true).to_vec();
+        buffer.extend_from_slice(&adler32(dictionary).to_be_bytes());
+
+        Ok(Self {
+            deflate_reader: DeflateReader::with_dictionary(
+                WithAdler32::new(inner),
+                options,
+                dictionary,
+            )?,
+            buffer,
+            buffer_pos: 0,
+            is_checksum_buffered: false,
+        })
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.deflate_reader.get_ref().get_ref()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.deflate_reader.into_inner().into_inner()
+    }
+}
+
+impl<R> io::Read for ZlibReader<R>
+where
+    R: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer_pos == self.buffer.len() && !self.is_checksum_buffered {
+            let bytes = self.deflate_reader.read(buf)?;
+            if bytes > 0 || buf.is_empty() {
+                return Ok(bytes);
+            }
+
+            let adler32 = self.deflate_reader.get_ref().adler32();
+            self.buffer = adler32.to_be_bytes().to_vec();
+            self.buffer_pos = 0;
+            self.is_checksum_buffered = true;
+        }
+
+        let bytes = (&self.buffer[self.buffer_pos..]).read(buf)?;
+        self.buffer_pos += bytes;
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ZlibDecoder {
+    dictionary: Option<Vec<u8>>,
+}
+
+impl ZlibDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a decoder for data that was encoded with a preset dictionary.
+    pub fn with_dictionary(dictionary: &[u8]) -> io::Result<Self> {
+        DeflateDecoder::with_dictionary(dictionary)?;
+
+        Ok(Self {
+            dictionary: Some(dictionary.to_vec()),
+        })
+    }
+
+    fn read_u32_be<R>(in_: &mut BitReader<R>) -> io::Result<u32>
+    where
+        R: io::Read,
+    {
+        Ok(in_.read_u32()?.swap_bytes())
+    }
+
+    pub fn decode<R, W>(&mut self, in_: &mut BitReader<R>, out: &mut W) -> io::Result<()>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        let cmf = in_.read_u8()?;
+        let flg = in_.read_u8()?;
+
+        let cm = cmf & 0x0f;
+        if cm != ZLIB_CM_DEFLATE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected CM={ZLIB_CM_DEFLATE:#02x}, got {cm:#02x}"),
+            ));
+        }
+
+        let cinfo = cmf >> 4;
+        if cinfo > ZLIB_MAX_CINFO {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected CINFO <= {ZLIB_MAX_CINFO}, got {cinfo}"),
+            ));
+        }
+
+        if u16::from_be_bytes([cmf, flg]) % 31 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("header check bits do not match; got CMF={cmf:#02x}, FLG={flg:#02x}"),
+            ));
+        }
+
+        let mut deflate_decoder = if flg & ZLIB_FDICT != 0 {
+            let dictid = Self::read_u32_be(in_)?;
+            let Some(dictionary) = &self.dictionary else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("data needs a preset dictionary with Adler-32 {dictid:#010x}"),
+                ));
+            };
+
+            let actual_dictid = adler32(dictionary);
+            if dictid != actual_dictid {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Adler-32 of preset dictionary does not match; got {actual_dictid:#010x}, expected {dictid:#010x}"),
+                ));
+            }
+
+            DeflateDecoder::with_dictionary(dictionary)?
+        } else {
+            DeflateDecoder::new()
+        };
+
+        let mut out_with_adler32 = WithAdler32::new(out);
+        deflate_decoder.decode(in_, &mut out_with_adler32)?;
+
+        let actual_adler32 = out_with_adler32.adler32();
+        let adler32 = Self::read_u32_be(in_)?;
+        if adler32 != actual_adler32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Adler-32 of zlib data does not match; got {actual_adler32:#010x}, expected {adler32:#010x}"),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deflate::{MemLevel, WindowBits},
+        test_utils::{pseudorandom_bytes, sample_text},
+    };
+    use std::io::Read;
+
+    fn decode(compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+        ZlibDecoder::new().decode(&mut BitReader::new(&mut &*compressed), &mut out)?;
+        Ok(out)
+    }
+
+    fn encode_with_options(data: &[u8], options: EncoderOptions) -> Vec<u8> {
+        let mut out = vec![];
+        ZlibEncoder::with_options(options)
+            .encode(&mut &*data, &mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_decode_zlib_output() {
+        // zlib output for "hello hello hello world" at the default level
+        let compressed = [
+            0x78, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x22, 0xcb, 0xf3, 0x8b,
+            0x72, 0x52, 0x00, 0x68, 0x7d, 0x08, 0xc5,
+        ];
+        assert_eq!(decode(&compressed).unwrap(), b"hello hello hello world");
+    }
+
+    #[test]
+    fn test_header() {
+        let data = sample_text();
+        let compressed = encode_with_options(&data, EncoderOptions::default());
+        assert_eq!(compressed[..2], [0x78, 0x9c]);
+        assert_eq!(decode(&compressed).unwrap(), data);
+
+        let compressed = encode_with_options(&data, CompressionLevel::BEST.into());
+        assert_eq!(compressed[..2], [0x78, 0xda]);
+
+        let compressed = encode_with_options(&data, CompressionLevel::FASTEST.into());
+        assert_eq!(compressed[..2], [0x78, 0x01]);
+    }
+
+    #[test]
+    fn test_window_bits_and_mem_level() {
+        let data = sample_text();
+
+        for bits in WindowBits::MIN.bits()..=WindowBits::MAX.bits() {
+            for mem_level in [MemLevel::MIN, MemLevel::DEFAULT, MemLevel::MAX] {
+                let options = EncoderOptions {
+                    window_bits: bits.try_into().unwrap(),
+                    mem_level,
+                    ..EncoderOptions::default()
+                };
+                let compressed = encode_with_options(&data, options);
+
+                assert_eq!(compressed[0] >> 4, bits - 8);
+                assert_eq!(decode(&compressed).unwrap(), data);
+            }
+        }
+
+        assert!(WindowBits::try_from(8).is_err());
+        assert!(WindowBits::try_from(16).is_err());
+        assert!(MemLevel::try_from(0).is_err());
+        assert!(MemLevel::try_from(10).is_err());
+    }
+
+    #[test]
+    fn test_preset_dictionary() {
+        let dictionary = b"anybody in there? Just nod";
+        let data = b"Is there anybody in there? Just nod if you can hear me.";
+
+        let mut compressed = vec![];
+        ZlibEncoder::with_dictionary(EncoderOptions::default(), dictionary)
+            .unwrap()
+            .encode(&mut &data[..], &mut compressed)
+            .unwrap();
+
+        assert!(decode(&compressed).is_err());
+
+        let mut out = vec![];
+        ZlibDecoder::with_dictionary(dictionary)
+            .unwrap()
+            .decode(&mut BitReader::new(&mut &*compressed), &mut out)
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_verify() {
+        let dictionary = b"it was the age of wisdom, it was the age of";
+        let data = sample_text();
+        let options = EncoderOptions {
+            verify: true,
+            ..EncoderOptions::default()
+        };
+
+        let compressed = encode_with_options(&data, options);
+        assert_eq!(decode(&compressed).unwrap(), data);
+
+        let mut compressed = vec![];
+        ZlibEncoder::with_dictionary(options, dictionary)
+            .unwrap()
+            .encode(&mut &*data, &mut compressed)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_verify_mismatch() {
+        let options = EncoderOptions {
+            verify: true,
+            ..EncoderOptions::default()
+        };
+
+        let mut encoder = ZlibEncoder::with_options(options);
+        encoder.deflate_encoder.corrupt_verification();
+        assert!(encoder.encode(&mut &*sample_text(), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_reader_matches_encoder() {
+        let dictionary = b"it was the age of wisdom, it was the age of";
+        let data = sample_text();
+
+        let mut compressed = vec![];
+        ZlibReader::new(&*data)
+            .read_to_end(&mut compressed)
+            .unwrap();
+        assert_eq!(
+            compressed,
+            encode_with_options(&data, EncoderOptions::default())
+        );
+
+        let mut expected = vec![];
+        ZlibEncoder::with_dictionary(EncoderOptions::default(), dictionary)
+            .unwrap()
+            .encode(&mut &*data, &mut expected)
+            .unwrap();
+        let mut compressed = vec![];
+        ZlibReader::with_dictionary(&*data, EncoderOptions::default(), dictionary)
+            .unwrap()
+            .read_to_end(&mut compressed)
+            .unwrap();
+        assert_eq!(compressed, expected);
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut compressed = encode_with_options(&sample_text(), EncoderOptions::default());
+        *compressed.last_mut().unwrap() ^= 1;
+        assert!(decode(&compressed).is_err());
+    }
+
+    #[test]
+    fn test_compress_bound() {
+        // Incompressible data, so that the bound is reached exactly
+        let data = pseudorandom_bytes(65535);
+
+        // Including input that fills a whole stored block
+        for len in [5000, data.len()] {
+            for level in [CompressionLevel::NONE, CompressionLevel::BEST] {
+                let mut encoder = ZlibEncoder::with_level(level);
+                let bound = encoder.compress_bound(len);
+                let mut compressed = vec![];
+                encoder.encode(&mut &data[..len], &mut compressed).unwrap();
+                assert_eq!(compressed.len(), bound);
+            }
+        }
+
+        // Incompressible input with many rsync points
+        let data = pseudorandom_bytes(200_000);
+        let options = EncoderOptions {
+            rsyncable: true,
+            ..EncoderOptions::default()
+        };
+        let mut encoder = ZlibEncoder::with_options(options);
+        let bound = encoder.compress_bound(data.len());
+        let mut compressed = vec![];
+        encoder.encode(&mut &*data, &mut compressed).unwrap();
+        assert!(compressed.len() <= bound, "{} > {bound}", compressed.len());
+
+        let encoder = ZlibEncoder::with_dictionary(EncoderOptions::default(), b"dict").unwrap();
+        assert_eq!(encoder.compress_bound(0), 2 + 4 + 5 + 4);
+    }
+}